@@ -0,0 +1,215 @@
+use std::{fs, io::Write, path::Path, process::Command};
+
+use serde::Deserialize;
+
+use super::*;
+use crate::{app::safe_join, util::{mkdirp, move_path, refuse_symlink, remove_tree}};
+
+/// Action file a template may ship at its root, compatible with degit's.
+/// It's run after extraction and then removed from the destination.
+pub const ACTIONS_FILE: &str = "degit.json";
+
+/// A step from `degit.json`, run in order after the template is extracted:
+///
+/// ```json
+/// [
+///   { "action": "clone", "src": "org/shared-ci", "dest": ".github" },
+///   { "action": "remove", "files": ["LICENSE", "docs"] },
+///   { "action": "rename", "from": "env.example", "to": ".env" },
+///   { "action": "append", "file": ".gitignore", "contents": "dist/\n" },
+///   { "action": "shell", "command": "npm install" }
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Action {
+    /// Clones another source over the destination, or into `dest` below it.
+    Clone {
+        src: String,
+        #[serde(default)]
+        dest: String,
+        /// Accepted for degit compatibility, the cache settings of the
+        /// outer clone apply.
+        #[serde(default)]
+        cache: Option<bool>,
+        #[serde(default)]
+        verbose: Option<bool>,
+    },
+    Remove {
+        #[serde(deserialize_with = "one_or_many")]
+        files: Vec<String>,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Append {
+        file: String,
+        contents: String,
+    },
+    /// Runs `command` with `sh -c` in the destination, only once confirmed.
+    Shell {
+        command: String,
+        #[serde(default)]
+        description: Option<String>,
+    },
+}
+
+/// Reads `degit.json` from `dest`, if the template shipped one.
+pub fn load(dest: &Path) -> Result<Option<Vec<Action>>, String> {
+    let path = dest.join(ACTIONS_FILE);
+    if !path.is_file() { return Ok(None) }
+    let contents = fs::read_to_string(&path).map_err(|e| format!("can't read '{}': {}", path.display(), e))?;
+    parse(&contents).map(Some)
+}
+
+pub fn parse(contents: &str) -> Result<Vec<Action>, String> {
+    serde_json::from_str(contents).map_err(|e| format!("invalid '{}': {}", ACTIONS_FILE, e))
+}
+
+/// Runs an action that only touches files in `dest`. `confirm` decides
+/// whether a shell hook may run. Clones are left to the caller, which owns
+/// the cache.
+pub fn apply(action: &Action, dest: &Path, confirm: &mut dyn FnMut(&str) -> bool) -> Result<(), String> {
+    let join = |rel: &str| safe_join(dest, rel)
+        .filter(|path| path != dest)
+        .ok_or_else(|| format!("'{}' points outside of the destination", rel));
+    match action {
+        Action::Clone { .. } => Err(String::from("clone actions need a cache, run them through `Regit`")),
+        Action::Remove { files } => {
+            for file in files {
+                let path = join(file)?;
+                if path.symlink_metadata().is_err() {
+                    warn!(format!("Nothing to remove at '{}'", file));
+                    continue;
+                }
+                remove_tree(&path, dest)?;
+                log!("removed    ", file);
+            }
+            Ok(())
+        },
+        Action::Rename { from, to } => {
+            let (source, target) = (join(from)?, join(to)?);
+            refuse_symlink(&source, from)?;
+            if target.symlink_metadata().is_ok() {
+                return Err(format!("can't rename '{}', '{}' already exists", from, to));
+            }
+            if let Some(parent) = target.parent() { mkdirp(parent) }
+            move_path(&source, &target).map_err(|e| format!("can't rename '{}' to '{}': {}", from, to, e))?;
+            log!("renamed    ", from, "->", to);
+            Ok(())
+        },
+        Action::Append { file, contents } => {
+            let path = join(file)?;
+            refuse_symlink(&path, file)?;
+            if let Some(parent) = path.parent() { mkdirp(parent) }
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut f| f.write_all(contents.as_bytes()))
+                .map_err(|e| format!("can't append to '{}': {}", file, e))?;
+            log!("appended   ", file);
+            Ok(())
+        },
+        Action::Shell { command, description } => {
+            if let Some(description) = description { info!(description); }
+            if !confirm(command) {
+                warn!(format!("Skipped hook `{}`", command));
+                return Ok(());
+            }
+            let status = Command::new("sh")
+                .args(["-c", command])
+                .current_dir(dest)
+                .status()
+                .map_err(|e| format!("can't run `{}`: {}", command, e))?;
+            match status.success() {
+                true => { log!("ran        ", command); Ok(()) },
+                false => Err(format!("hook `{}` failed with {}", command, status)),
+            }
+        },
+    }
+}
+
+/// Accepts a single file as well as a list of them, as degit does.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany { One(String), Many(Vec<String>) }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(file) => vec![file],
+        OneOrMany::Many(files) => files,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_degit_actions() {
+        let actions = parse(r#"[
+            { "action": "clone", "src": "user/another-repo" },
+            { "action": "remove", "files": "LICENSE" },
+            { "action": "remove", "files": ["a", "b"] }
+        ]"#).unwrap();
+        assert_eq!(actions, vec![
+            Action::Clone { src: "user/another-repo".into(), dest: String::new(), cache: None, verbose: None },
+            Action::Remove { files: vec!["LICENSE".into()] },
+            Action::Remove { files: vec!["a".into(), "b".into()] },
+        ]);
+        assert!(parse(r#"[{ "action": "launch" }]"#).is_err());
+    }
+
+    #[test]
+    fn applies_file_actions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path();
+        mkdirp(&dest.join("docs"));
+        fs::write(dest.join("docs/a.md"), "").unwrap();
+        fs::write(dest.join("LICENSE"), "").unwrap();
+        fs::write(dest.join("env.example"), "A=1\n").unwrap();
+        let mut never = |_: &str| false;
+
+        apply(&Action::Remove { files: vec!["docs".into(), "LICENSE".into(), "missing".into()] }, dest, &mut never).unwrap();
+        assert!(!dest.join("docs").exists() && !dest.join("LICENSE").exists());
+
+        apply(&Action::Rename { from: "env.example".into(), to: "config/.env".into() }, dest, &mut never).unwrap();
+        apply(&Action::Append { file: "config/.env".into(), contents: "B=2\n".into() }, dest, &mut never).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("config/.env")).unwrap(), "A=1\nB=2\n");
+
+        assert!(apply(&Action::Remove { files: vec!["../outside".into()] }, dest, &mut never).is_err());
+        assert!(apply(&Action::Remove { files: vec![".".into()] }, dest, &mut never).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_writing_through_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (dest, outside) = (tmp.path().join("dest"), tmp.path().join("outside.txt"));
+        mkdirp(&dest);
+        fs::write(&outside, "mine\n").unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join("x")).unwrap();
+        let mut never = |_: &str| false;
+
+        assert!(apply(&Action::Append { file: "x".into(), contents: "theirs\n".into() }, &dest, &mut never).is_err());
+        assert!(apply(&Action::Rename { from: "x".into(), to: "y".into() }, &dest, &mut never).is_err());
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "mine\n");
+        assert!(dest.join("x").symlink_metadata().is_ok() && !dest.join("y").exists());
+    }
+
+    #[test]
+    fn runs_hooks_only_when_confirmed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let hook = Action::Shell { command: "echo ran > hook.txt".into(), description: None };
+
+        apply(&hook, tmp.path(), &mut |_| false).unwrap();
+        assert!(!tmp.path().join("hook.txt").exists());
+
+        apply(&hook, tmp.path(), &mut |_| true).unwrap();
+        assert_eq!(fs::read_to_string(tmp.path().join("hook.txt")).unwrap(), "ran\n");
+        assert!(apply(&Action::Shell { command: "exit 3".into(), description: None }, tmp.path(), &mut |_| true).is_err());
+    }
+}
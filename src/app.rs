@@ -1,151 +1,2257 @@
 #![allow(dead_code)]
 
-use std::path::Path;
+use std::{collections::BTreeMap, fs, io::{IsTerminal, Read}, path::{Component, Path, PathBuf}, process::Command, time::{SystemTime, UNIX_EPOCH}};
 
 use super::*;
+use async_recursion::async_recursion;
 use crate::{
-    options::ValidModes,
-    repository::Repository, cache::Cache, util::{mkdirp, fetch}, traits::{AsStr, AsString}
+    actions::{self, Action, ACTIONS_FILE},
+    archive::{self, ArchiveFormat, EntryKind},
+    filter::Filter,
+    merge::{self, diff_trees, list_files, merge_trees},
+    options::{ValidModes, ConflictStrategy, FileOutcome, Layer, GitInit, KeepGit, LayerConflict, MergeOutcome, MtimePolicy, Output},
+    patch::{self, FilePatch, PatchResult},
+    provenance::Provenance,
+    template::{Ask, Template, TemplateManifest, TemplateVars, Variable, TEMPLATE_FILE},
+    repository::{is_commit_hash, Repository}, cache::Cache, config::Config, util::{mkdirp, fetch, git_remote, token_for, prompt, backup_path, remove_tree, sha256_file}, traits::AsStr
 };
 
+const MIRROR_DIR: &str = "mirror.git";
+const ARCHIVE_REF: &str = "archive";
+/// How deep `clone` actions may nest before a template is assumed to clone itself.
+const MAX_ACTION_DEPTH: usize = 8;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct RegitOptions {
-    cache: bool,
-    force: bool,
-    verbose: bool,
-    has_stashed: bool,
+    pub cache: bool,
+    pub conflict: ConflictStrategy,
+    pub verbose: bool,
+    pub dry_run: bool,
+    pub mtime: MtimePolicy,
+    /// Mode bits cleared from every extracted file and directory.
+    pub umask: u32,
+    /// Expected SHA-256 of the downloaded archive.
+    pub sha256: Option<String>,
+    /// Mode forced on hosted repositories, `Tar` or `Git`.
+    pub mode: Option<ValidModes>,
+    /// Cache location instead of `~/.regit`.
+    pub cache_dir: Option<String>,
+    pub proxy: String,
+    /// Access tokens by host.
+    pub auth: BTreeMap<String, String>,
+    /// Whether `Overwrite` moves the destination's previous contents aside
+    /// first, rather than only replacing the files the clone writes.
+    pub stash: bool,
+    /// Whether a `.regit.json` recording the source is written to the destination.
+    pub provenance: bool,
+    /// Variables to render the template with, `None` to copy it verbatim.
+    pub template: Option<TemplateVars>,
+    /// Run shell hooks from `degit.json` without asking.
+    pub hooks: bool,
+    /// Whether to ask on a terminal about hooks and missing template
+    /// variables. Off for clones running side by side, whose questions
+    /// would interleave; hooks are then skipped unless `hooks` is set.
+    pub interactive: bool,
+    /// Which template files to extract.
+    pub filter: Filter,
+    /// Unified diffs applied to the extracted files, in order.
+    pub patches: Vec<String>,
+    /// Writes the selected files to an archive instead of a directory.
+    pub output: Option<Output>,
+    /// Makes the destination a git repository with an initial commit.
+    pub git_init: Option<GitInit>,
+    /// Keeps the template's git history in the destination, git sources only.
+    pub keep_git: Option<KeepGit>,
+    /// Settings sources are resolved with: default host, mirrors, aliases.
+    pub config: Config,
+    pub has_stashed: bool,
 }
 impl Default for RegitOptions {
     fn default() -> Self {
         Self { 
             cache: true, 
-            force: false, 
+            conflict: ConflictStrategy::Abort,
             verbose: false,
+            dry_run: false,
+            mtime: MtimePolicy::Preserve,
+            umask: 0o022,
+            sha256: None,
+            mode: None,
+            cache_dir: None,
+            proxy: String::new(),
+            auth: BTreeMap::new(),
+            stash: true,
+            provenance: true,
+            template: None,
+            hooks: false,
+            filter: Filter::default(),
+            patches: vec![],
+            output: None,
+            git_init: None,
+            keep_git: None,
+            config: Config::default(),
+            interactive: true,
             has_stashed: false
         }
     }
 }
 
+/// How a scaffolded directory relates to its template, as reported by
+/// `regit status`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub provenance: Provenance,
+    /// Commit the ref points at now, unknown for archives and local copies.
+    pub latest: Option<String>,
+}
+impl Status {
+    pub fn is_behind(&self) -> bool {
+        self.latest.is_some() && self.latest.as_deref() != self.provenance.commit.as_deref()
+    }
+}
+
+/// What a clone would fetch and write, as reported by a dry run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClonePlan {
+    pub domain: String,
+    pub _ref: String,
+    pub hash: String,
+    pub archive_url: String,
+    pub cached: bool,
+    /// Per-file outcomes, only known when the archive is already cached.
+    pub files: Option<Vec<(String, FileOutcome)>>,
+}
+
 #[derive(Debug, Default)]
 pub struct Regit {
     src: String,
+    /// `src` with aliases expanded.
+    resolved: String,
     repo: Repository,
     cache: Cache,
     options: RegitOptions,
+    /// Commit, or archive SHA-256, the last clone extracted.
+    hash: Option<String>,
+    /// SHA-256 of the archive the last clone extracted.
+    archive_sha256: Option<String>,
+    /// Template variables the last clone rendered with.
+    vars: BTreeMap<String, String>,
+    /// Number of `clone` actions this clone is nested in.
+    depth: usize,
 }
 
 impl Regit {
     pub fn new(src: &str, options: RegitOptions) -> Self {
+        let cache = Cache::at(options.cache_dir.as_deref()).load();
+        Self::with_cache(src, options, cache)
+    }
+
+    /// Like `new`, but shares `cache` with other clones.
+    pub fn with_cache(src: &str, options: RegitOptions, cache: Cache) -> Self {
+        let resolved = options.config.expand_alias(src);
+        if resolved != src {
+            info!(format!("Expanding alias '{}' to '{}'", src, resolved));
+        }
+        Self::with_resolved(src, resolved, options, cache)
+    }
+
+    /// Clones what a scaffolded directory was cloned from, as resolved back
+    /// then.
+    fn from_provenance(provenance: &Provenance, options: RegitOptions) -> Self {
+        let cache = Cache::at(options.cache_dir.as_deref()).load();
+        Self::with_resolved(&provenance.source, provenance.resolved_source().to_owned(), options, cache)
+    }
+
+    fn with_resolved(src: &str, resolved: String, options: RegitOptions, cache: Cache) -> Self {
+        let mut repo = Repository::parse(&resolved, &options.config);
+        if let Some(mode @ (ValidModes::Tar | ValidModes::Git)) = options.mode {
+            if matches!(repo.mode, ValidModes::Tar | ValidModes::Git) { repo.mode = mode }
+        }
+        // Tarballs carry no history.
+        if options.keep_git.is_some() && repo.mode == ValidModes::Tar { repo.mode = ValidModes::Git }
         Self {
             src: src.into(),
-            repo: Repository::parse(src),
-            cache: Cache::new().load(),
+            resolved,
+            repo,
+            cache,
             options,
+            hash: None,
+            archive_sha256: None,
+            vars: BTreeMap::new(),
+            depth: 0,
+        }
+    }
+
+    /// Commit, or archive SHA-256, extracted by the last clone. Copies of
+    /// local directories have none.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    /// Clones exactly `hash`, as previously returned by `hash()`, instead of
+    /// resolving the ref again.
+    pub fn pin(&mut self, hash: &str) {
+        match self.repo.mode {
+            ValidModes::Archive => self.options.sha256 = Some(hash.to_owned()),
+            ValidModes::Local if self.repo._ref.is_empty() => {},
+            _ => self.repo._ref = hash.to_owned(),
         }
     }
 
-    pub async fn clone(&mut self, dest: &str) {
+    pub async fn clone(&mut self, dest: &str) -> Result<(), String> {
+        self.repo.resolve(&self.options.config)?;
+        if self.options.dry_run {
+            let plan = self.plan(dest)?;
+            Self::print_plan(&plan);
+            return Ok(());
+        }
         info!("Cloning repository...");
-        let Repository {
-            domain,
-            user,
-            name,
-            _ref, ..
-        } = &self.repo;
+        if self.options.verbose && self.repo.urls() != [self.repo.url()] {
+            debug!(format!("'{}' is rewritten to:", self.repo.url));
+            for url in self.repo.urls() { debug!("  ", url); }
+        }
+
+        if let Some(output) = self.options.output.clone() {
+            return self.clone_to_archive(&output).await;
+        }
 
         // let dest_path= std::path::absolute(dest).unwrap();
         let dest_path= Path::new(dest);
-        if !dest_path.exists() {
-            warn!(format!("'{}' doesn't exist! Attempting to create path...", dest_path.as_str()));
-            mkdirp(&dest_path);
+        let keep_git = self.options.keep_git;
+        let history = match keep_git {
+            Some(keep) => Some(self.history_dir(keep)?),
+            None => None,
+        };
+        // Read before the destination is stashed, they may live in there.
+        let patches = Self::load_patches(&self.options.patches, Path::new("."))?;
+        self.options.patches = self.options.patches.iter().map(|p| Self::patch_path(p, dest_path)).collect();
+        Self::prepare_dest(dest_path, &mut self.options, &self.cache)?;
+        if history.is_some() && dest_path.join(".git").exists() {
+            return Err(format!("'{}' is a git repository already, can't keep the template's history in it", dest));
+        }
+
+        let outcomes = self.extract_into(dest_path).await?;
+        if let (Some(keep), Some(git_dir)) = (keep_git, &history) {
+            self.keep_history(dest_path, git_dir, keep)?;
+        }
+        let extracted_actions = outcomes.iter().any(|(file, outcome)| {
+            file == ACTIONS_FILE && !matches!(outcome, FileOutcome::Skipped | FileOutcome::Conflict)
+        });
+        if extracted_actions {
+            self.run_actions(dest_path).await?;
+        }
+        let patched = Self::apply_patches(&patches, dest_path)?;
+        if self.options.provenance {
+            self.provenance().save(dest_path)?;
+        }
+        Self::print_summary(&outcomes);
+
+        Self::check_patched(&patched)?;
+        if let Some(init) = &self.options.git_init {
+            Self::git_init(dest_path, init, &Self::origin_trailer(&self.provenance()))?;
+        }
+        done!("Repository successfully cloned. Happy coding!");
+        Ok(())
+    }
+
+    /// Writes the template to `output` instead of a directory. Nothing gets
+    /// patched, actions aren't run and no `.regit.json` is recorded.
+    async fn clone_to_archive(&mut self, output: &Output) -> Result<(), String> {
+        if !self.options.patches.is_empty() {
+            return Err(String::from("patches can't be applied when writing an archive"));
+        }
+        let outcomes = self.extract_into(Path::new(".")).await?;
+        if outcomes.iter().any(|(file, _)| file == ACTIONS_FILE) {
+            warn!(format!("'{}' is packed as is, its actions aren't run", ACTIONS_FILE));
+        }
+        Self::print_summary(&outcomes);
+        match output.is_stdout() {
+            true => { done!("Archive written to stdout"); },
+            false => { done!(format!("Archive written to '{}'", output.path)); },
+        }
+        Ok(())
+    }
+
+    /// Makes `dest` a git repository with everything in it as the first
+    /// commit, whose message ends with `trailer`. Inside an existing work tree
+    /// the files are only staged, the commit is left to the user.
+    fn git_init(dest: &Path, init: &GitInit, trailer: &str) -> Result<(), String> {
+        let git = |args: &[&str]| run_git(dest, args);
+        if let Ok(top) = git(&["rev-parse", "--show-toplevel"]) {
+            git(&["add", "-A", "."])?;
+            warn!(format!("'{}' is inside the repository at '{}', files are staged but not committed", dest.display(), top));
+            return Ok(());
+        }
+
+        if let Some(branch) = &init.branch {
+            git(&["check-ref-format", "--branch", branch]).map_err(|_| format!("'{}' isn't a valid branch name", branch))?;
+        }
+        git(&["init", "-q"])?;
+        if let Some(branch) = &init.branch {
+            git(&["symbolic-ref", "HEAD", &format!("refs/heads/{}", branch)])?;
+        }
+        git(&["add", "-A"])?;
+        let message = format!("{}\n\n{}", init.message.trim_end(), trailer);
+        match &init.identity {
+            Some((name, email)) => git(&[
+                "-c", &format!("user.name={}", name), "-c", &format!("user.email={}", email),
+                "commit", "-q", "-m", &message,
+            ])?,
+            None => git(&["commit", "-q", "-m", &message])?,
+        };
+        success!("Initialized git repository with the template as its first commit");
+        Ok(())
+    }
+
+    /// Git directory holding the history `--keep-git` keeps: the cached mirror
+    /// in git mode, or the local repository.
+    fn history_dir(&self, keep: KeepGit) -> Result<PathBuf, String> {
+        let repo = &self.repo;
+        if keep == KeepGit::Full && !repo.sub_dir.is_empty() {
+            return Err(format!("the full history is of the whole repository, use --keep-git=subtree to keep the one of '{}'", repo.sub_dir));
+        }
+        match repo.mode {
+            ValidModes::Git => Ok(Path::new(self.cache.dir()).join(repo.cache_path()).join(MIRROR_DIR)),
+            ValidModes::Local if !repo._ref.is_empty() => Ok(Self::local_git_dir(Path::new(&repo.url))),
+            _ => Err(String::from("--keep-git needs a git repository as the source")),
+        }
+    }
+
+    /// Makes `dest` a repository at the extracted commit, checked out on its
+    /// branch when the ref is one, with `origin` pointing at the source. With
+    /// `KeepGit::Subtree` the history is rewritten to the subdirectory's, as
+    /// `git subtree split` does. Only the index is reset, the extracted files
+    /// stay as they were written.
+    fn keep_history(&self, dest: &Path, git_dir: &Path, keep: KeepGit) -> Result<(), String> {
+        let repo = &self.repo;
+        let hash = self.hash.clone().ok_or("the extracted commit is unknown")?;
+        let git = |args: &[&str]| run_git(dest, args);
+        let git_dir = fs::canonicalize(git_dir).map_err(|e| format!("can't read '{}': {}", git_dir.display(), e))?;
+        let source = git_dir.to_string_lossy();
+        let branch = match repo._ref.as_str() {
+            "" | "HEAD" => run_git(&git_dir, &["symbolic-ref", "--short", "HEAD"]).ok(),
+            r => run_git(&git_dir, &["rev-parse", "--verify", "-q", &format!("refs/heads/{}", r)]).ok().map(|_| r.to_owned()),
+        };
+        let origin = match repo.mode {
+            ValidModes::Local => fs::canonicalize(&repo.url).map_or_else(|_| repo.url.clone(), |path| path.to_string_lossy().into_owned()),
+            _ => repo.url(),
+        };
+
+        git(&["init", "-q"])?;
+        git(&["remote", "add", "origin", &origin])?;
+        let head = match keep {
+            KeepGit::Subtree if !repo.sub_dir.is_empty() => {
+                info!(format!("Splitting the history of '{}'...", repo.sub_dir));
+                git(&["fetch", "-q", "--no-tags", &source, &hash])?;
+                // `subtree split` wants the prefix in a work tree, which
+                // `dest` doesn't have as it holds the prefix's contents.
+                let scratch = self.cache.temp_dir();
+                mkdirp(&scratch.join(&repo.sub_dir));
+                let dest_git = fs::canonicalize(dest.join(".git")).map_err(|e| e.to_string())?;
+                let split = run_git(&scratch, &[
+                    "--git-dir", &dest_git.to_string_lossy(), "--work-tree", ".",
+                    "subtree", "split", "-q", &format!("--prefix={}", repo.sub_dir), &hash,
+                ]);
+                self.cache.remove_temp_dir(&scratch);
+                split?
+            },
+            _ => {
+                log!("Fetching history...");
+                git(&["fetch", "-q", "--no-tags", &source, &hash, "+refs/heads/*:refs/remotes/origin/*"])?;
+                hash
+            },
+        };
+        match &branch {
+            Some(branch) => {
+                git(&["update-ref", &format!("refs/heads/{}", branch), &head])?;
+                git(&["symbolic-ref", "HEAD", &format!("refs/heads/{}", branch)])?;
+                // A split history has nothing in common with origin's branch.
+                if keep == KeepGit::Full || repo.sub_dir.is_empty() {
+                    git(&["branch", "-q", &format!("--set-upstream-to=origin/{}", branch), branch]).ok();
+                }
+            },
+            None => { git(&["update-ref", "--no-deref", "HEAD", &head])?; },
+        }
+        git(&["reset", "-q"])?;
+        success!(format!("Kept git history at {}", &head[..head.len().min(12)]));
+        Ok(())
+    }
+
+    /// Where the scaffold came from, as recorded in the initial commit.
+    fn origin_trailer(provenance: &Provenance) -> String {
+        let mut trailer = format!("Template: {}", provenance.resolved_source());
+        match (&provenance.commit, &provenance.archive_sha256) {
+            (Some(commit), _) => trailer += &format!("\nCommit: {}", commit),
+            (None, Some(sha256)) => trailer += &format!("\nArchive-SHA256: {}", sha256),
+            (None, None) => {},
+        }
+        trailer
+    }
+
+    /// Reads and parses `patches`, relative to `base` unless absolute.
+    fn load_patches(patches: &[String], base: &Path) -> Result<Vec<(String, Vec<FilePatch>)>, String> {
+        patches.iter()
+            .map(|path| {
+                let contents = fs::read_to_string(base.join(path)).map_err(|e| format!("can't read patch '{}': {}", path, e))?;
+                let patch = patch::parse(&contents).map_err(|e| format!("invalid patch '{}': {}", path, e))?;
+                Ok((path.clone(), patch))
+            })
+            .collect()
+    }
+
+    /// How a patch is recorded in `.regit.json`: relative to `dest` when it
+    /// lives in there, so the directory can move, and absolute otherwise.
+    fn patch_path(patch: &str, dest: &Path) -> String {
+        let patch = resolve_existing(Path::new(patch));
+        match patch.strip_prefix(resolve_existing(dest)) {
+            Ok(relative) => relative.to_string_lossy().into_owned(),
+            Err(_) => patch.to_string_lossy().into_owned(),
+        }
+    }
+
+    fn apply_patches(patches: &[(String, Vec<FilePatch>)], root: &Path) -> Result<Vec<PatchResult>, String> {
+        let mut results = vec![];
+        for (path, patch) in patches {
+            info!(format!("Applying patch '{}'...", path));
+            for result in patch::apply(patch, root, patch::DEFAULT_FUZZ)? {
+                match (&result.rej, result.fuzzed) {
+                    (Some(rej), _) => { warn!(format!("{} of {} hunks rejected in '{}', see '{}'", result.rejected, result.applied + result.rejected, result.file, rej)); },
+                    (None, 0) => { log!("patched    ", &result.file); },
+                    (None, fuzzed) => { log!("patched    ", &result.file, format!("({} hunks with fuzz)", fuzzed)); },
+                }
+                results.push(result);
+            }
+        }
+        Ok(results)
+    }
+
+    fn check_patched(results: &[PatchResult]) -> Result<(), String> {
+        match results.iter().map(|r| r.rejected).sum::<usize>() {
+            0 => Ok(()),
+            n => Err(format!("{} patch hunks rejected, apply them by hand from the '.rej' files", n)),
+        }
+    }
+
+    /// Creates `dest` if needed and checks `options.conflict` allows writing
+    /// into it, stashing its contents first when overwriting.
+    fn prepare_dest(dest: &Path, options: &mut RegitOptions, cache: &Cache) -> Result<(), String> {
+        if !dest.exists() {
+            warn!(format!("'{}' doesn't exist! Attempting to create path...", dest.as_str()));
+            mkdirp(dest);
+        }
+        let is_empty = dest.read_dir().map_err(|e| e.to_string())?.next().is_none();
+        if !is_empty && options.conflict == ConflictStrategy::Abort {
+            return Err(format!(
+                "Destination '{}' not empty! Use --force, --merge, --backup or --interactive",
+                dest.display()
+            ));
+        }
+
+        if !is_empty && options.conflict == ConflictStrategy::Overwrite && options.stash {
+            let stash = cache.stash_files(dest)?;
+            options.has_stashed = true;
+            info!(format!("Previous contents stashed as '{}'. Run `regit unstash {}` to restore them.", stash.id, dest.display()));
+        }
+        Ok(())
+    }
+
+    /// Applies `layers` in order into `dest`. A file shipped by several
+    /// layers comes from the last of them, or with `LayerConflict::Error`
+    /// the composition is refused when their versions differ. Every layer is
+    /// extracted on its own, pinned to its entry in `pins` if any, and the
+    /// stack then written to `dest` like a single template. Returns the hash
+    /// each layer was extracted at. Layered destinations get no `.regit.json`
+    /// and their `degit.json` actions aren't run. A dry run only plans each
+    /// layer, see `plan_layers`.
+    pub async fn compose(
+        layers: &[Layer],
+        pins: &[Option<String>],
+        dest: &str,
+        mut options: RegitOptions,
+        conflict: LayerConflict,
+        cache: Cache,
+    ) -> Result<Vec<Option<String>>, String> {
+        info!(format!("Composing {} layers...", layers.len()));
+        if options.dry_run {
+            return Self::plan_layers(layers, pins, dest, &options, &cache);
+        }
+        let patches = Self::load_patches(&options.patches, Path::new("."))?;
+        let staging = cache.temp_dir();
+        let stacked = async {
+            let mut origins = BTreeMap::new();
+            let mut conflicts = vec![];
+            let mut hashes = vec![];
+            for (i, layer) in layers.iter().enumerate() {
+                let layer_options = RegitOptions {
+                    conflict: ConflictStrategy::Overwrite,
+                    stash: false,
+                    provenance: false,
+                    filter: Filter::new(&layer.include, &layer.exclude)?,
+                    // Layers are stacked in staging, which is packed as a whole.
+                    output: None,
+                    ..options.clone()
+                };
+                let mut regit = Self::for_layer(layer, pins.get(i), layer_options, &cache)?;
+
+                let dir = cache.temp_dir();
+                let stacked = async {
+                    regit.extract_into(&dir).await?;
+                    for file in list_files(&dir)? {
+                        if file == ACTIONS_FILE {
+                            warn!(format!("Skipping '{}' of '{}', actions of layers aren't run", file, layer.src));
+                            continue;
+                        }
+                        let (from, to) = (dir.join(&file), staging.join(&file));
+                        if let Some(&previous) = origins.get(&file) {
+                            if conflict == LayerConflict::Error && merge::read(&from) != merge::read(&to) {
+                                let previous: &Layer = &layers[previous];
+                                conflicts.push(format!("{} ({} and {})", file, previous.src, layer.src));
+                            }
+                        }
+                        merge::replace(&from, &to)?;
+                        origins.insert(file, i);
+                    }
+                    Ok::<_, String>(())
+                }.await;
+                cache.remove_temp_dir(&dir);
+                stacked?;
+                hashes.push(regit.hash.clone());
+            }
+            if !conflicts.is_empty() {
+                return Err(format!("{} files differ between layers:\n  {}", conflicts.len(), conflicts.join("\n  ")));
+            }
+
+            let opts = ExtractOptions { template: None, filter: Filter::default(), ..(&options).into() };
+            if let Some(output) = &options.output {
+                if !patches.is_empty() {
+                    return Err(String::from("patches can't be applied when writing an archive"));
+                }
+                let outcomes = Self::pack(&staging, "", &opts, output)?;
+                Self::print_summary_from(&outcomes, |file| origins.get(file).map(|&i| layers[i].src.as_str()));
+                return Ok(hashes);
+            }
+            let dest_path = Path::new(dest);
+            Self::prepare_dest(dest_path, &mut options, &cache)?;
+            let outcomes = Self::untar(&staging, dest_path, "", &opts, &mut Self::prompt_conflict)?;
+            Self::check_patched(&Self::apply_patches(&patches, dest_path)?)?;
+            Self::print_summary_from(&outcomes, |file| origins.get(file).map(|&i| layers[i].src.as_str()));
+            Ok(hashes)
+        }.await;
+        cache.remove_temp_dir(&staging);
+
+        let hashes = stacked?;
+        if options.output.is_some() { return Ok(hashes) }
+        if let Some(init) = &options.git_init {
+            let trailer = layers.iter().zip(&hashes)
+                .map(|(layer, hash)| match hash {
+                    Some(hash) => format!("Layer: {} at {}", layer.src, hash),
+                    None => format!("Layer: {}", layer.src),
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            Self::git_init(Path::new(dest), init, &trailer)?;
+        }
+        done!("Layers successfully composed. Happy coding!");
+        Ok(hashes)
+    }
+
+    /// The clone of one layer, pinned to `pin` if there is one.
+    fn for_layer(layer: &Layer, pin: Option<&Option<String>>, options: RegitOptions, cache: &Cache) -> Result<Self, String> {
+        let mut regit = Self::with_cache(&layer.src, options, cache.clone());
+        regit.repo.resolve(&regit.options.config)?;
+        if let Some(sub_dir) = &layer.sub_dir { regit.repo.sub_dir = sub_dir.trim_matches('/').to_owned() }
+        if let Some(Some(pin)) = pin { regit.pin(pin) }
+        Ok(regit)
+    }
+
+    /// Dry run of `compose`: resolves every layer and lists what it would
+    /// write to `dest` when its archive is cached, files shipped by several
+    /// layers coming from the last. Nothing is downloaded or extracted, so
+    /// differing layers aren't detected and no hashes are returned.
+    fn plan_layers(
+        layers: &[Layer],
+        pins: &[Option<String>],
+        dest: &str,
+        options: &RegitOptions,
+        cache: &Cache,
+    ) -> Result<Vec<Option<String>>, String> {
+        info!("Dry run, nothing will be fetched or written");
+        let mut files: BTreeMap<String, (FileOutcome, usize)> = BTreeMap::new();
+        for (i, layer) in layers.iter().enumerate() {
+            let layer_options = RegitOptions {
+                stash: false,
+                provenance: false,
+                output: None,
+                filter: Filter::new(&layer.include, &layer.exclude)?,
+                ..options.clone()
+            };
+            let plan = Self::for_layer(layer, pins.get(i), layer_options, cache)?.plan(dest)?;
+            info!(format!("Layer {}: {}", i + 1, layer.src));
+            Self::print_plan_source(&plan);
+            match plan.files {
+                Some(planned) => for (file, outcome) in planned {
+                    if file != ACTIONS_FILE { files.insert(file, (outcome, i)); }
+                },
+                None => { warn!("Archive isn't cached yet, can't list its files without downloading it"); },
+            }
+        }
+        let outcomes: Vec<_> = files.iter().map(|(file, (outcome, _))| (file.clone(), outcome.clone())).collect();
+        Self::print_summary_from(&outcomes, |file| files.get(file).map(|&(_, i)| layers[i].src.as_str()));
+        Ok(vec![None; layers.len()])
+    }
+
+    /// Runs the `degit.json` the template extracted into `dest`, then removes
+    /// it. `clone` actions go through the same cache, recursively.
+    #[async_recursion]
+    async fn run_actions(&mut self, dest: &Path) -> Result<(), String> {
+        let Some(steps) = actions::load(dest)? else { return Ok(()) };
+        fs::remove_file(dest.join(ACTIONS_FILE)).map_err(|e| e.to_string())?;
+        info!(format!("Running {} template actions...", steps.len()));
+
+        let (hooks, interactive) = (self.options.hooks, self.options.interactive);
+        let mut confirm = |command: &str| {
+            hooks || (interactive && std::io::stdin().is_terminal() && matches!(
+                prompt(&format!("The template wants to run `{}`. Run it? [y/N]", command)).to_lowercase().as_str(),
+                "y" | "yes"
+            ))
+        };
+        for step in &steps {
+            let Action::Clone { src, dest: sub_dir, verbose, .. } = step else {
+                actions::apply(step, dest, &mut confirm)?;
+                continue;
+            };
+            if self.depth >= MAX_ACTION_DEPTH {
+                return Err(format!("'{}' nests clone actions more than {} levels deep", src, MAX_ACTION_DEPTH));
+            }
+            let target = safe_join(dest, sub_dir).ok_or_else(|| format!("'{}' points outside of the destination", sub_dir))?;
+            let options = RegitOptions {
+                conflict: ConflictStrategy::Overwrite,
+                stash: false,
+                provenance: false,
+                verbose: verbose.unwrap_or(self.options.verbose),
+                patches: vec![],
+                git_init: None,
+                keep_git: None,
+                has_stashed: false,
+                ..self.options.clone()
+            };
+            let mut child = Self::with_cache(src, options, self.cache.clone());
+            child.depth = self.depth + 1;
+            child.clone(target.as_str()).await.map_err(|e| format!("clone action '{}' failed: {}", src, e))?;
+        }
+        Ok(())
+    }
+
+    /// Brings the directory scaffolded into `dest` up to date with the latest
+    /// version of its template. Both versions are extracted from the cache
+    /// and the upstream changes merged into `dest`, keeping local edits.
+    pub async fn update(dest: &str, options: RegitOptions, reject: bool) -> Result<(), String> {
+        let dest = Path::new(dest);
+        let provenance = Provenance::load(dest)?;
+        info!(format!("Updating '{}' from '{}'...", dest.display(), provenance.source));
+        let (pin, mut recorded, mut latest) = Self::recorded_and_latest(&provenance, options)?;
+
+        let patches = Self::load_patches(&recorded.options.patches, dest)?;
+
+        let (base, theirs) = (latest.cache.temp_dir(), latest.cache.temp_dir());
+        let merged = async {
+            recorded.extract_into(&base).await?;
+            Self::apply_patches(&patches, &base)?;
+            latest.extract_into(&theirs).await?;
+            let patched = Self::apply_patches(&patches, &theirs)?;
+            match latest.hash.as_deref() {
+                Some(hash) if hash == pin => Ok(None),
+                _ => {
+                    let mut outcomes = merge_trees(&base, &theirs, dest, reject)?;
+                    // Hunks that no longer apply upstream come along as `.rej` files.
+                    for rejected in patched.iter().filter(|r| r.rej.is_some()) {
+                        outcomes.retain(|(file, _)| Some(file) != rejected.rej.as_ref());
+                        outcomes.push((rejected.file.clone(), MergeOutcome::Rejected(rejected.rej.clone().unwrap())));
+                    }
+                    Ok::<_, String>(Some(outcomes))
+                },
+            }
+        }.await;
+        latest.cache.remove_temp_dir(&base);
+        latest.cache.remove_temp_dir(&theirs);
+
+        let Some(outcomes) = merged? else {
+            done!(format!("Already up to date at '{}'", pin));
+            return Ok(());
+        };
+        latest.provenance().save(dest)?;
+        Self::print_merge_summary(&outcomes);
+
+        match outcomes.iter().any(|(_, o)| matches!(o, MergeOutcome::Conflict | MergeOutcome::Rejected(_))) {
+            true => Err(String::from("Updated with conflicts, resolve them before committing")),
+            false => { done!("Template changes applied. Happy coding!"); Ok(()) },
+        }
+    }
+
+    /// Tells whether the ref recorded for the directory scaffolded into
+    /// `dest` has moved since. Only resolves refs, nothing is downloaded.
+    pub fn status(dest: &str, options: RegitOptions) -> Result<Status, String> {
+        let provenance = Provenance::load(Path::new(dest))?;
+        let latest = Self::from_provenance(&provenance, options).latest_hash()?;
+        Ok(Status { provenance, latest })
+    }
+
+    /// Unified diff from the recorded template version to the local files in
+    /// `dest`, or with `upstream` to the latest template version. Files the
+    /// template doesn't have are left out of the local diff.
+    pub async fn diff(dest: &str, options: RegitOptions, upstream: bool) -> Result<String, String> {
+        let dest = Path::new(dest);
+        let provenance = Provenance::load(dest)?;
+        let (_, mut recorded, mut latest) = Self::recorded_and_latest(&provenance, options)?;
+
+        let patches = Self::load_patches(&recorded.options.patches, dest)?;
+
+        let (base, theirs) = (recorded.cache.temp_dir(), recorded.cache.temp_dir());
+        let diff = async {
+            recorded.extract_into(&base).await?;
+            Self::apply_patches(&patches, &base)?;
+            match upstream {
+                true => {
+                    latest.extract_into(&theirs).await?;
+                    Self::apply_patches(&patches, &theirs)?;
+                    diff_trees(&base, &theirs, false)
+                },
+                false => diff_trees(&base, dest, true),
+            }
+        }.await;
+        recorded.cache.remove_temp_dir(&base);
+        recorded.cache.remove_temp_dir(&theirs);
+        diff
+    }
+
+    /// The recorded template version of a scaffolded directory, pinned, and
+    /// the latest one, both extracting silently over whatever is there.
+    fn recorded_and_latest(provenance: &Provenance, options: RegitOptions) -> Result<(String, Self, Self), String> {
+        let pin = provenance.pin().ok_or("a copied local directory has no recorded version")?.to_owned();
+        let template = match provenance.vars.is_empty() {
+            true => options.template,
+            false => Some(TemplateVars { vars: provenance.vars.clone(), prompt: true }),
+        };
+        let options = RegitOptions {
+            conflict: ConflictStrategy::Overwrite,
+            stash: false,
+            dry_run: false,
+            template,
+            patches: provenance.patches.clone(),
+            ..options
+        };
+        let mut latest = Self::from_provenance(provenance, options.clone());
+        latest.repo.resolve(&latest.options.config)?;
+        let mut recorded = Self {
+            src: latest.src.clone(),
+            resolved: latest.resolved.clone(),
+            repo: latest.repo.clone(),
+            cache: latest.cache.clone(),
+            options,
+            hash: None,
+            archive_sha256: None,
+            vars: BTreeMap::new(),
+            depth: 0,
+        };
+        recorded.pin(&pin);
+        Ok((pin, recorded, latest))
+    }
+
+    /// Commit the ref resolves to upstream right now, when that can be told
+    /// without downloading anything. Never falls back to the cached hash, an
+    /// unreachable upstream is an error.
+    fn latest_hash(&mut self) -> Result<Option<String>, String> {
+        self.repo.resolve(&self.options.config)?;
+        let repo = &self.repo;
+        match repo.mode {
+            ValidModes::Tar | ValidModes::Git => Self::resolve_remote_ref(&repo.urls(), &repo._ref, &self.options).map(Some),
+            ValidModes::Local if !repo._ref.is_empty() => {
+                Self::resolve_git_ref(&Self::local_git_dir(Path::new(&repo.url)), &repo._ref).map(Some)
+            },
+            ValidModes::Archive | ValidModes::Local => Ok(None),
+        }
+    }
+
+    /// What the last clone extracted, for `.regit.json`.
+    fn provenance(&self) -> Provenance {
+        let repo = &self.repo;
+        Provenance {
+            source: self.src.clone(),
+            resolved: self.resolved.clone(),
+            url: repo.url(),
+            sub_dir: repo.sub_dir.clone(),
+            _ref: repo._ref.clone(),
+            commit: self.hash.clone().filter(|_| repo.mode != ValidModes::Archive),
+            archive_sha256: self.archive_sha256.clone(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            vars: self.vars.clone(),
+            patches: self.options.patches.clone(),
         }
+    }
 
-        let repo_dir = Path::new(self.cache.dir())
-            .join(format!("{}/{}/{}", domain, user, name));
+    /// Fetches the template through the cache and extracts it into `dest`.
+    async fn extract_into(&mut self, dest: &Path) -> Result<Vec<(String, FileOutcome)>, String> {
+        self.repo.resolve(&self.options.config)?;
+        let repo_dir = Path::new(self.cache.dir()).join(self.repo.cache_path());
 
         if ! repo_dir.exists() { mkdirp(&repo_dir) }
+        let _lock = self.cache.lock(&repo_dir).await;
         match self.repo.mode {
-            ValidModes::Tar => self.clone_with_tar(&repo_dir, &dest_path).await,
-            ValidModes::Git => self.clone_with_git(dest)
+            ValidModes::Tar => self.clone_with_tar(&repo_dir, dest).await,
+            ValidModes::Git => self.clone_with_git(&repo_dir, dest),
+            ValidModes::Archive => self.clone_with_archive(&repo_dir, dest).await,
+            ValidModes::Local => self.clone_with_local(&repo_dir, dest),
         }
+    }
 
-        done!("Repository successfully cloned. Happy coding!");
+    /// Resolves the commit and archive for this source and, when the archive
+    /// is already cached, lists what extracting it into `dest` would do.
+    /// Doesn't fetch archives or write anything.
+    pub fn plan(&mut self, dest: &str) -> Result<ClonePlan, String> {
+        self.repo.resolve(&self.options.config)?;
+        let repo = &self.repo;
+        let repo_dir = Path::new(self.cache.dir()).join(repo.cache_path());
+        let mirror = repo_dir.join(MIRROR_DIR);
+
+        let (hash, archive_url) = match repo.mode {
+            ValidModes::Tar => {
+                let hash = repo.get_hash_cached(&self.cache)?;
+                let archive_url = repo.archive_url(&hash);
+                (hash, archive_url)
+            },
+            ValidModes::Git => {
+                let hash = match mirror.is_dir() {
+                    true => Self::resolve_git_ref(&mirror, &repo._ref)?,
+                    false => Self::resolve_remote_ref(&repo.urls(), &repo._ref, &self.options)?,
+                };
+                (hash, format!("git archive of {}", repo.url()))
+            },
+            ValidModes::Archive if repo.is_local() => (sha256_file(Path::new(&repo.url))?, repo.url()),
+            ValidModes::Archive => {
+                let hash = self.cache.get_cached_hash(repo_dir.as_str(), ARCHIVE_REF);
+                (hash.unwrap_or_else(|| "unknown until downloaded".into()), repo.url())
+            },
+            ValidModes::Local if repo._ref.is_empty() => (String::from("working tree"), repo.url()),
+            ValidModes::Local => {
+                let git_dir = Self::local_git_dir(Path::new(&repo.url));
+                (Self::resolve_git_ref(&git_dir, &repo._ref)?, format!("git archive of {}", repo.url()))
+            },
+        };
+        let file = match repo.mode {
+            ValidModes::Archive if repo.is_local() => Some(PathBuf::from(&repo.url)),
+            ValidModes::Local if repo._ref.is_empty() => Some(PathBuf::from(&repo.url)),
+            _ => archive::find_cached(&repo_dir, &hash),
+        };
+        let cached = file.is_some();
+
+        let files = match file {
+            Some(file) => Some(Self::untar(
+                &file,
+                Path::new(dest),
+                &self.archive_prefix(&file)?,
+                &ExtractOptions {
+                    dry_run: true,
+                    template: self.options.template.as_ref().map(|t| Template::new(t.vars.clone())),
+                    ..(&self.options).into()
+                },
+                &mut |_| ConflictStrategy::Interactive,
+            )?),
+            None => None,
+        };
+
+        Ok(ClonePlan {
+            domain: repo.domain.to_owned(),
+            _ref: repo._ref.to_owned(),
+            hash,
+            archive_url,
+            cached,
+            files,
+        })
     }
 }
 
 impl Regit {
-    fn clone_with_git(&self, dest: &str) {
-        println!("Cloning with git to {}...", dest);
-        cmd!("git", ["clone", &self.repo.url(), dest]);
+    /// Keeps a bare mirror of the repository under `repo_dir`, fetches into it
+    /// incrementally and exports the resolved commit as a cached tarball, so
+    /// extraction goes through the same path as tar mode.
+    fn clone_with_git(&mut self, repo_dir: &Path, dest: &Path) -> Result<Vec<(String, FileOutcome)>, String> {
+        info!("Cloning repository in Git mode...");
+        let repo = self.repo.to_owned();
+        let mirror = repo_dir.join(MIRROR_DIR);
+
+        Self::sync_mirror(&repo.urls(), &mirror, &self.options)?;
+        let hash = Self::resolve_git_ref(&mirror, &repo._ref)?;
+        self.export_and_extract(&mirror, repo_dir, dest, &hash)
+    }
+
+    /// Copies a local directory as it is on disk, or exports a local git
+    /// repository at the requested ref without touching the network.
+    fn clone_with_local(&mut self, repo_dir: &Path, dest: &Path) -> Result<Vec<(String, FileOutcome)>, String> {
+        let repo = self.repo.to_owned();
+        let src = Path::new(&repo.url);
+        if repo._ref.is_empty() {
+            info!("Copying local directory...");
+            let src_abs = fs::canonicalize(src).map_err(|e| format!("can't read '{}': {}", repo.url, e))?;
+            if self.options.output.is_none() && fs::canonicalize(dest).map_err(|e| e.to_string())?.starts_with(src_abs) {
+                return Err(String::from("Destination can't be inside the source directory"));
+            }
+            return self.extract(src, dest);
+        }
+
+        info!("Exporting local repository...");
+        let git_dir = Self::local_git_dir(src);
+        let hash = Self::resolve_git_ref(&git_dir, &repo._ref)?;
+        self.export_and_extract(&git_dir, repo_dir, dest, &hash)
+    }
+
+    fn local_git_dir(root: &Path) -> PathBuf {
+        match root.join(".git") {
+            dot_git if dot_git.exists() => dot_git,
+            _ => root.to_owned(),
+        }
+    }
+
+    /// Exports `hash` from `git_dir` into a cached tarball unless it's there
+    /// already, then extracts it.
+    fn export_and_extract(&mut self, git_dir: &Path, repo_dir: &Path, dest: &Path, hash: &str) -> Result<Vec<(String, FileOutcome)>, String> {
+        let repo = self.repo.to_owned();
+        let file = repo_dir.join(format!("{}.tar.gz", hash));
+        self.hash = Some(hash.to_owned());
+
+        if !(self.options.cache && file.is_file()) {
+            log!(format!("Exporting '{}' from '{}'...", hash, git_dir.as_str()));
+            let prefix = format!("{}-{}/", repo.name, hash);
+            let output = cmd!("git", [
+                "--git-dir", git_dir.as_str(),
+                "archive", "--format=tar.gz",
+                &format!("--prefix={}", prefix),
+                "-o", file.as_str(),
+                hash,
+            ]);
+            if !output.status.success() {
+                return Err(format!("git archive failed for '{}': {}", hash, String::from_utf8_lossy(&output.stderr).trim()));
+            }
+        } else {
+            success!("File found in cache! Using it to make things faster...");
+        }
+
+        self.cache.update(&repo._ref, hash, repo_dir.as_str());
+        self.extract(&file, dest)
+    }
+
+    /// Creates or updates the bare mirror from the first of `urls` that
+    /// answers. The mirror fetches from explicit URLs rather than `origin`, so
+    /// changing the rewrite rules takes effect on the next clone.
+    fn sync_mirror(urls: &[String], mirror: &Path, options: &RegitOptions) -> Result<(), String> {
+        if mirror.is_dir() {
+            log!(format!("Fetching into mirror '{}'...", mirror.as_str()));
+            for url in urls {
+                let output = git_remote(url, &options.proxy, &options.auth)
+                    .args(["--git-dir", mirror.as_str(), "fetch", "--prune", url, "+refs/*:refs/*"])
+                    .output();
+                if output.is_ok_and(|o| o.status.success()) { return Ok(()) }
+                warn!(format!("Couldn't fetch from '{}'", url));
+            }
+            warn!("Couldn't update mirror, falling back to cached refs.");
+            return Ok(());
+        }
+
+        for url in urls {
+            log!(format!("Creating mirror of '{}'...", url));
+            match git_remote(url, &options.proxy, &options.auth).args(["clone", "--mirror", url, mirror.as_str()]).output() {
+                Ok(output) if output.status.success() => return Ok(()),
+                Ok(output) => { error!(String::from_utf8_lossy(&output.stderr)); },
+                Err(e) => { error!(format!("can't run git: {}", e)); },
+            }
+        }
+        Err(format!("failed to mirror '{}'", urls.join("', '")))
+    }
+
+    fn resolve_git_ref(git_dir: &Path, repo_ref: &str) -> Result<String, String> {
+        let output = cmd!("git", [
+            "--git-dir", git_dir.as_str(),
+            "rev-parse", "--verify", &format!("{}^{{commit}}", repo_ref),
+        ]);
+        if !output.status.success() {
+            return Err(format!("ref '{}' not found in '{}'", repo_ref, git_dir.display()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    /// Commit `repo_ref` points to on the first of `urls` that answers. Only
+    /// exact branch and tag names match, annotated tags are peeled and full
+    /// hashes are taken as they are.
+    fn resolve_remote_ref(urls: &[String], repo_ref: &str, options: &RegitOptions) -> Result<String, String> {
+        if is_commit_hash(repo_ref) { return Ok(repo_ref.to_owned()) }
+        let patterns = match repo_ref {
+            "HEAD" => vec![String::from("HEAD")],
+            r => vec![format!("refs/heads/{}", r), format!("refs/tags/{}", r), format!("refs/tags/{}^{{}}", r)],
+        };
+        for url in urls {
+            let output = git_remote(url, &options.proxy, &options.auth).args(["ls-remote", url]).args(&patterns).output();
+            let Some(output) = output.ok().filter(|o| o.status.success()) else {
+                warn!(format!("Couldn't list refs of '{}'", url));
+                continue;
+            };
+            return Repository::find_ref(&String::from_utf8_lossy(&output.stdout), repo_ref)
+                .ok_or_else(|| format!("ref '{}' not found in '{}'", repo_ref, url));
+        }
+        Err(format!("couldn't list refs of '{}'", urls.join("', '")))
+    }
 
-        let git = format!("{}/.git", dest);
-        println!("Removing .git directory from {}...", dest);
-        cmd!("rm", ["-rf", &git]);
+    /// Downloads the first of `urls` that succeeds into `dest`, through the
+    /// configured proxy and with the token configured for each URL's host.
+    async fn fetch_any(&self, urls: &[String], dest: &Path) -> Result<(), String> {
+        let mut error = String::from("nothing to fetch");
+        for url in urls {
+            match fetch(url, dest.as_str(), &self.options.proxy, token_for(&self.options.auth, url)).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!(format!("Couldn't fetch '{}': {}", url, e));
+                    error = e;
+                }
+            }
+        }
+        Err(error)
     }
 
-    async fn clone_with_tar(&mut self, repo_dir: &Path, dest: &Path) {
+    async fn clone_with_tar(&mut self, repo_dir: &Path, dest: &Path) -> Result<Vec<(String, FileOutcome)>, String> {
         info!("Cloning repository in Tar mode...");
         let repo = self.repo.to_owned();
-        let hash = repo.get_hash_cached(&self.cache);
+        let hash = repo.get_hash_cached(&self.cache)?;
         let archive_url = repo.archive_url(&hash);
+        self.hash = Some(hash.clone());
 
         log!("Archive url is", &archive_url, "...");
-        let sub_dir = format!("{}-{}{}", repo.name, hash, repo.sub_dir);
-        let file = Path::new(&format!("{}/{}.tar.gz", repo_dir.as_str(), hash)).to_owned();
 
-        if !dest.exists() { mkdirp(dest) }
-        if dest.read_dir().unwrap().count() > 0 {
-            error!(format!("Destination '{}' not empty!", dest.display())); panic!("dir not empty");
+        if let Some(file) = archive::find_cached(repo_dir, &hash).filter(|_| self.options.cache) {
+            success!("File found in cache! Using it to make things faster...");
+            return self.extract(&file, dest);
         }
-        if file.exists() && file.is_file() {
-            if self.options.cache {
-                success!("File found in cache! Using it to make things faster...");
-                Self::untar(&file, &dest, &sub_dir);
-                return;
+        mkdirp(repo_dir);
+
+        let download = repo_dir.join(format!("{}.download", hash));
+        self.fetch_any(&repo.archive_urls(&hash), &download).await?;
+        let format = ArchiveFormat::detect(&download)?;
+        let file = repo_dir.join(format!("{}.{}", hash, format.extension()));
+        fs::rename(&download, &file).map_err(|e| e.to_string())?;
+
+        self.cache.update(&repo._ref, &hash, repo_dir.as_str());
+        self.extract(&file, dest)
+    }
+
+    /// Fetches a plain archive into the cache, keyed by its SHA-256, or uses
+    /// a local archive in place.
+    async fn clone_with_archive(&mut self, repo_dir: &Path, dest: &Path) -> Result<Vec<(String, FileOutcome)>, String> {
+        info!("Cloning from archive...");
+        let repo = self.repo.to_owned();
+        if repo.is_local() {
+            let file = PathBuf::from(&repo.url);
+            let hash = sha256_file(&file)?;
+            self.verify_sha256(&hash)?;
+            self.hash = Some(hash);
+            return self.extract(&file, dest);
+        }
+
+        let cached = self.cache.get_cached_hash(repo_dir.as_str(), ARCHIVE_REF)
+            .and_then(|hash| Some((archive::find_cached(repo_dir, &hash)?, hash)))
+            .filter(|(_, hash)| self.options.cache && self.verify_sha256(hash).is_ok());
+        if let Some((file, hash)) = cached {
+            success!("File found in cache! Using it to make things faster...");
+            self.hash = Some(hash);
+            return self.extract(&file, dest);
+        }
+
+        let download = repo_dir.join("archive.download");
+        self.fetch_any(&repo.urls(), &download).await?;
+        let hash = sha256_file(&download)?;
+        if let Err(e) = self.verify_sha256(&hash) {
+            fs::remove_file(&download).ok();
+            return Err(e);
+        }
+        self.hash = Some(hash.clone());
+        let format = ArchiveFormat::detect(&download)?;
+        let file = repo_dir.join(format!("{}.{}", hash, format.extension()));
+        fs::rename(&download, &file).map_err(|e| e.to_string())?;
+
+        self.cache.update(ARCHIVE_REF, &hash, repo_dir.as_str());
+        self.extract(&file, dest)
+    }
+
+    fn verify_sha256(&self, hash: &str) -> Result<(), String> {
+        match &self.options.sha256 {
+            Some(expected) if !expected.eq_ignore_ascii_case(hash) => Err(format!(
+                "SHA-256 mismatch: expected '{}', got '{}'", expected, hash
+            )),
+            Some(_) => { success!("SHA-256 verified"); Ok(()) },
+            None => Ok(()),
+        }
+    }
+
+    fn extract(&mut self, file: &Path, dest: &Path) -> Result<Vec<(String, FileOutcome)>, String> {
+        self.archive_sha256 = match file.is_file() {
+            true => Some(sha256_file(file)?),
+            false => None,
+        };
+        let sub_dir = self.archive_prefix(file)?;
+        let template = self.template(file, &sub_dir)?;
+        let opts = ExtractOptions { template, ..(&self.options).into() };
+        match &self.options.output {
+            Some(output) => Self::pack(file, &sub_dir, &opts, output),
+            None => Self::untar(file, dest, &sub_dir, &opts, &mut Self::prompt_conflict),
+        }
+    }
+
+    /// Settles the template variables when templating is on, checking them
+    /// against the template's `regit.template.toml` and asking for missing
+    /// ones on a terminal.
+    fn template(&mut self, file: &Path, prefix: &str) -> Result<Option<Template>, String> {
+        let Some(given) = &self.options.template else { return Ok(None) };
+        let manifest = TemplateManifest::read(file, prefix)?.unwrap_or_default();
+        let mut ask = Self::prompt_variable;
+        let ask: Option<&mut Ask> = match given.prompt && self.options.interactive && std::io::stdin().is_terminal() {
+            true => Some(&mut ask),
+            false => None,
+        };
+        self.vars = manifest.resolve(&given.vars, ask)?;
+        Ok(Some(Template::new(self.vars.clone())))
+    }
+
+    fn prompt_variable(name: &str, variable: &Variable) -> String {
+        let mut question = name.to_owned();
+        if let Some(description) = &variable.description { question += &format!(" ({})", description) }
+        if !variable.choices.is_empty() { question += &format!(" [{}]", variable.choices.join("/")) }
+        if let Some(default) = &variable.default { question += &format!(" [default: {}]", default) }
+        prompt(&format!("{}:", question))
+    }
+
+    /// Path prefix of the selected subdirectory inside `file`. Every host names
+    /// its top-level directory differently (`<name>-<hash>/` on GitHub,
+    /// `<name>-<ref>-<hash>/` on GitLab, ...) and plain archives may not have one.
+    fn archive_prefix(&self, file: &Path) -> Result<String, String> {
+        let repo = &self.repo;
+        let root = match repo.mode {
+            ValidModes::Local if repo._ref.is_empty() => String::new(),
+            _ => archive::common_prefix(file)?,
+        };
+        Ok(match repo.sub_dir.as_str() {
+            "" => root,
+            sub_dir => format!("{}{}/", root, sub_dir),
+        })
+    }
+
+    fn prompt_conflict(file: &str) -> ConflictStrategy {
+        loop {
+            let answer = prompt(&format!("'{}' already exists. [o]verwrite, [s]kip, [b]ackup or [a]bort?", file));
+            match answer.to_lowercase().as_str() {
+                "o" | "overwrite" => return ConflictStrategy::Overwrite,
+                "s" | "skip" => return ConflictStrategy::Merge,
+                "b" | "backup" => return ConflictStrategy::Backup,
+                "a" | "abort" => return ConflictStrategy::Abort,
+                _ => { warn!("Please answer 'o', 's', 'b' or 'a'"); },
             }
-        } else { mkdirp(file.parent().unwrap()); }
-        
-        
-        fetch(&archive_url, file.as_str(), "").await.unwrap();
-        self.cache.update(&hash, &repo._ref, repo_dir.as_str());
-        Self::untar(&file, &dest, &sub_dir);
+        }
     }
 
-    fn clone_from_cache(&self, dest: &str) {
+    fn print_summary(outcomes: &[(String, FileOutcome)]) {
+        Self::print_summary_from(outcomes, |_| None)
+    }
 
+    /// Like `print_summary`, naming the source each file came from.
+    fn print_summary_from<'a>(outcomes: &[(String, FileOutcome)], origin: impl Fn(&str) -> Option<&'a str>) {
+        let count = |f: fn(&FileOutcome) -> bool| outcomes.iter().filter(|(_, o)| f(o)).count();
+        for (file, outcome) in outcomes {
+            let from = origin(file).map(|src| format!("[{}]", src)).unwrap_or_default();
+            match outcome {
+                FileOutcome::Created => { log!("created    ", file, &from); },
+                FileOutcome::Overwritten => { log!("overwritten", file, &from); },
+                FileOutcome::Skipped => { log!("skipped    ", file, &from); },
+                FileOutcome::BackedUp(backup) => { log!("backed up  ", file, "->", backup, &from); },
+                FileOutcome::Conflict => { log!("conflict   ", file, &from); },
+            }
+        }
+        info!(format!(
+            "{} created, {} overwritten, {} skipped, {} backed up",
+            count(|o| *o == FileOutcome::Created),
+            count(|o| *o == FileOutcome::Overwritten),
+            count(|o| *o == FileOutcome::Skipped),
+            count(|o| matches!(o, FileOutcome::BackedUp(_))),
+        ));
     }
-}
 
-impl Regit {
-    fn untar(file: &Path, dest: &Path, sub_dir: &str) {
-        // let archive_name = file.file_prefix().unwrap().to_str().unwrap();
-        // let target = format!("{}/{}", dest.as_str(), sub_dir);
-        // info!(format!("Extracting '...{}' to '{}'", file.as_str(), dest.as_str()));
+    fn print_merge_summary(outcomes: &[(String, MergeOutcome)]) {
+        let count = |f: fn(&MergeOutcome) -> bool| outcomes.iter().filter(|(_, o)| f(o)).count();
+        for (file, outcome) in outcomes {
+            match outcome {
+                MergeOutcome::Added => { log!("added      ", file); },
+                MergeOutcome::Updated => { log!("updated    ", file); },
+                MergeOutcome::Deleted => { log!("deleted    ", file); },
+                MergeOutcome::Merged => { log!("merged     ", file); },
+                MergeOutcome::Conflict => { warn!(format!("conflict    {}", file)); },
+                MergeOutcome::Rejected(rej) => { warn!(format!("rejected    {} -> {}", file, rej)); },
+                MergeOutcome::Kept(reason) => { log!("kept       ", file, format!("({})", reason)); },
+            }
+        }
+        info!(format!(
+            "{} added, {} updated, {} deleted, {} merged, {} conflicts, {} kept",
+            count(|o| *o == MergeOutcome::Added),
+            count(|o| *o == MergeOutcome::Updated),
+            count(|o| *o == MergeOutcome::Deleted),
+            count(|o| *o == MergeOutcome::Merged),
+            count(|o| matches!(o, MergeOutcome::Conflict | MergeOutcome::Rejected(_))),
+            count(|o| matches!(o, MergeOutcome::Kept(_))),
+        ));
+    }
 
-        let file = std::fs::File::open(file).expect("error opening file");
-        let stream = flate2::read::GzDecoder::new(file);
-        let mut archive = tar::Archive::new(stream);
+    fn print_plan_source(plan: &ClonePlan) {
+        log!("host:       ", &plan.domain);
+        log!("ref:        ", &plan._ref);
+        log!("commit:     ", &plan.hash);
+        log!("archive:    ", &plan.archive_url);
+        log!("cached:     ", if plan.cached { "yes" } else { "no" });
+    }
 
-        // let tar_prefix = format!("{}-{}", repo.user, repo.name, repo.sub_dir)
+    fn print_plan(plan: &ClonePlan) {
+        info!("Dry run, nothing will be fetched or written");
+        Self::print_plan_source(plan);
+        match &plan.files {
+            Some(files) => Self::print_summary(files),
+            None => { warn!("Archive isn't cached yet, can't list files without downloading it"); },
+        }
+    }
+}
+
+impl Regit {
+    /// Extracts the entries under `sub_dir` into `dest`, resolving files that
+    /// already exist with `opts.strategy`. `ask` is consulted in interactive mode.
+    /// Works the same for every format `archive::open` can read.
+    fn untar(
+        file: &Path,
+        dest: &Path,
+        sub_dir: &str,
+        opts: &ExtractOptions,
+        ask: &mut dyn FnMut(&str) -> ConflictStrategy,
+    ) -> Result<Vec<(String, FileOutcome)>, String> {
+        let mut archive = archive::open(file)?;
         let untar_location = sub_dir;
 
-        let mut count_unpacked = 0usize;
+        let mut outcomes = vec![];
+        let mut dirs = vec![];
+        let mut commit_time = None;
         debug!(format!("Unpacking '{}'...", untar_location));
-        for entry in archive.entries().unwrap() {
-            let mut entry = entry.unwrap();
-            let entry_path = entry.path().unwrap().as_string();
+        archive.for_each_entry(&mut |entry, contents| {
+            // Archives from every supported host stamp entries with the commit time.
+            let commit_time = *commit_time.get_or_insert(entry.mtime);
+
+            let Some(untar_file) = Self::select_entry(entry, untar_location, opts) else { return Ok(()) };
+            let untar_file = untar_file.as_str();
+            let Some(file_path) = safe_join(dest, untar_file) else {
+                warn!(format!("Skipping '{}', it points outside of the destination", untar_file));
+                return Ok(());
+            };
+            let untar_file = untar_file.trim_end_matches('/');
+            let is_dir = entry.kind == EntryKind::Dir;
+
+            let mut outcome = FileOutcome::Created;
+            if !is_dir && file_path.symlink_metadata().is_ok() {
+                let strategy = match opts.strategy {
+                    ConflictStrategy::Interactive => ask(untar_file),
+                    strategy => strategy,
+                };
+                outcome = match strategy {
+                    ConflictStrategy::Abort | ConflictStrategy::Interactive if opts.dry_run => FileOutcome::Conflict,
+                    ConflictStrategy::Abort => return Err(format!("'{}' already exists, aborting", untar_file)),
+                    ConflictStrategy::Merge => FileOutcome::Skipped,
+                    ConflictStrategy::Overwrite => FileOutcome::Overwritten,
+                    ConflictStrategy::Backup => {
+                        let backup = backup_path(&file_path);
+                        if !opts.dry_run {
+                            fs::rename(&file_path, &backup).map_err(|e| e.to_string())?;
+                        }
+                        FileOutcome::BackedUp(backup.file_name().unwrap().to_string_lossy().into())
+                    },
+                    ConflictStrategy::Interactive => unreachable!("prompt must pick a strategy"),
+                };
+            }
+
+            if opts.dry_run {
+                if !is_dir {
+                    outcomes.push((untar_file.to_owned(), outcome));
+                }
+                return Ok(());
+            }
+            if outcome != FileOutcome::Skipped {
+                log!(format!("Extracting '{}' to '{}'...", untar_file, file_path.display()));
+                let written = match (&opts.template, &entry.kind) {
+                    (Some(_), EntryKind::File) => {
+                        let bytes = Self::read_contents(untar_file, contents, opts)?;
+                        Self::write_entry(&file_path, dest, &entry.kind, &mut &bytes[..])
+                    },
+                    _ => Self::write_entry(&file_path, dest, &entry.kind, contents),
+                };
+                written.map_err(|e| format!("failed to extract '{}': {}", untar_file, e))?;
 
-            if entry_path.starts_with(&untar_location) {
-                let untar_file = entry_path.replace(&untar_location, "");
-                let file_path = format!("{}/{}", dest.as_str(), untar_file);
-                log!(format!("Extracting '{}' to '{}'...", untar_file, file_path));
-                entry.unpack(&file_path).expect(" should extract to destination");
-                count_unpacked += 1;
+                let mtime = opts.mtime.resolve(entry.mtime, commit_time);
+                match is_dir {
+                    // Writing children bumps a directory's mtime, so apply it last.
+                    true => dirs.push((file_path, mtime)),
+                    false => Self::apply_metadata(&file_path, entry.mode, mtime, opts.umask)
+                        .map_err(|e| format!("failed to set metadata of '{}': {}", untar_file, e))?,
+                }
+            }
+            if !is_dir {
+                outcomes.push((untar_file.to_owned(), outcome));
             }
+            Ok(())
+        })?;
+        for (dir, mtime) in dirs.into_iter().rev() {
+            Self::apply_metadata(&dir, 0o755, mtime, opts.umask)
+                .map_err(|e| format!("failed to set metadata of '{}': {}", dir.display(), e))?;
+        }
+        if outcomes.is_empty() { warn!("No files unpacked"); }
+        else { success!(&format!("Unpacked {} files", outcomes.len())); }
+        Ok(outcomes)
+    }
+
+    /// Where `entry` goes relative to the destination, or `None` when it's
+    /// outside `sub_dir`, the template manifest or filtered out. Paths are
+    /// rendered when templating.
+    fn select_entry(entry: &archive::Entry, sub_dir: &str, opts: &ExtractOptions) -> Option<String> {
+        let file = entry.path.strip_prefix(sub_dir)?;
+        if file.is_empty() { return None }
+        let file = match &opts.template {
+            Some(_) if file == TEMPLATE_FILE => return None,
+            Some(template) => template.render(file),
+            None => file.to_owned(),
+        };
+        // Parent directories of matching files are created on the way.
+        if !opts.filter.is_empty() && (entry.kind == EntryKind::Dir || !opts.filter.matches(&file)) {
+            return None;
+        }
+        Some(file)
+    }
+
+    /// Reads the contents of a file entry, rendered when templating.
+    fn read_contents(file: &str, contents: &mut dyn Read, opts: &ExtractOptions) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        contents.read_to_end(&mut bytes).map_err(|e| format!("failed to read '{}': {}", file, e))?;
+        Ok(match &opts.template {
+            Some(template) => template.render_bytes(bytes),
+            None => bytes,
+        })
+    }
+
+    /// Writes the entries `untar` would extract to `output` as an archive.
+    /// Every entry is stamped with `SOURCE_DATE_EPOCH` when set, or else with
+    /// the newest mtime among them, which for hosted archives is the commit time.
+    fn pack(file: &Path, sub_dir: &str, opts: &ExtractOptions, output: &Output) -> Result<Vec<(String, FileOutcome)>, String> {
+        let mut entries = BTreeMap::new();
+        let mut newest = 0;
+        archive::open(file)?.for_each_entry(&mut |entry, contents| {
+            let Some(path) = Self::select_entry(entry, sub_dir, opts) else { return Ok(()) };
+            if !Path::new(&path).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+                warn!(format!("Skipping '{}', it points outside of the destination", path));
+                return Ok(());
+            }
+            let contents = match entry.kind {
+                EntryKind::File => Self::read_contents(&path, contents, opts)?,
+                _ => vec![],
+            };
+            newest = newest.max(entry.mtime);
+            let packed = archive::PackEntry { kind: entry.kind.clone(), mode: entry.mode, contents };
+            entries.insert(path.trim_end_matches('/').to_owned(), packed);
+            Ok(())
+        })?;
+        let mtime = std::env::var("SOURCE_DATE_EPOCH").ok()
+            .and_then(|epoch| epoch.parse().ok())
+            .unwrap_or(newest);
+
+        let bytes = archive::build(output.format, &entries, mtime)?;
+        let written = match output.is_stdout() {
+            true => std::io::Write::write_all(&mut std::io::stdout().lock(), &bytes),
+            false => fs::write(&output.path, &bytes),
+        };
+        written.map_err(|e| format!("can't write '{}': {}", output.path, e))?;
+
+        let outcomes: Vec<_> = entries.into_iter()
+            .filter(|(_, entry)| entry.kind != EntryKind::Dir)
+            .map(|(path, _)| (path, FileOutcome::Created))
+            .collect();
+        if outcomes.is_empty() { warn!("No files packed"); }
+        else { success!(&format!("Packed {} files", outcomes.len())); }
+        Ok(outcomes)
+    }
+
+    /// Writes one entry at `path`, replacing whatever is there as long as it
+    /// lies within `dest`.
+    fn write_entry(path: &Path, dest: &Path, kind: &EntryKind, contents: &mut dyn Read) -> std::io::Result<()> {
+        if *kind == EntryKind::Dir {
+            return fs::create_dir_all(path);
+        }
+        if let Some(parent) = path.parent() { fs::create_dir_all(parent)? }
+        // Never write through an existing link or onto a directory.
+        if path.symlink_metadata().is_ok() {
+            remove_tree(path, dest).map_err(std::io::Error::other)?;
+        }
+        match kind {
+            EntryKind::Symlink(target) => {
+                #[cfg(unix)]
+                std::os::unix::fs::symlink(target, path)?;
+                Ok(())
+            },
+            _ => std::io::copy(contents, &mut fs::File::create(path)?).map(|_| ()),
+        }
+    }
+
+    /// Keeps only the executable bit from the archive, masks the mode with
+    /// `umask` and stamps `mtime`. Symlinks are left as they are.
+    fn apply_metadata(path: &Path, mode: u32, mtime: SystemTime, umask: u32) -> std::io::Result<()> {
+        let meta = path.symlink_metadata()?;
+        if meta.file_type().is_symlink() { return Ok(()) }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let executable = meta.is_dir() || mode & 0o111 != 0;
+            let mode = if executable { 0o777 } else { 0o666 };
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & !umask))?;
+        }
+        fs::File::open(path)?.set_modified(mtime)
+    }
+}
+
+/// Joins an archive path onto `dest`, refusing absolute paths, `..` and
+/// parents that resolve outside of `dest` through symlinks.
+pub(crate) fn safe_join(dest: &Path, rel: &str) -> Option<PathBuf> {
+    let rel = Path::new(rel);
+    if !rel.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return None;
+    }
+    let path = dest.join(rel);
+    let parent = path.parent().unwrap_or(dest);
+    resolve_existing(parent).starts_with(resolve_existing(dest)).then_some(path)
+}
+
+/// Runs git in `dir`, returning its trimmed output.
+fn run_git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .arg("-C").arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| format!("can't run git: {}", e))?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned()),
+        false => Err(format!("`git {}` failed: {}", args[0], String::from_utf8_lossy(&output.stderr).trim())),
+    }
+}
+
+/// Canonicalizes the longest existing ancestor of `path` and appends the rest.
+fn resolve_existing(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut rest = vec![];
+    loop {
+        if let Ok(dir) = fs::canonicalize(existing) {
+            return rest.iter().rev().fold(dir, |dir, name| dir.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => { rest.push(name); existing = parent; },
+            _ => return path.to_owned(),
+        }
+    }
+}
+
+/// Settings for a single `Regit::untar` run.
+#[derive(Debug, Clone)]
+struct ExtractOptions {
+    strategy: ConflictStrategy,
+    dry_run: bool,
+    mtime: MtimePolicy,
+    umask: u32,
+    /// Renders paths and text contents when set.
+    template: Option<Template>,
+    filter: Filter,
+}
+impl From<&RegitOptions> for ExtractOptions {
+    fn from(options: &RegitOptions) -> Self {
+        Self {
+            strategy: options.conflict,
+            dry_run: options.dry_run,
+            mtime: options.mtime,
+            umask: options.umask,
+            template: None,
+            filter: options.filter.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{options::OutputFormat, test_util::git};
+
+    fn local_repo(dir: &Path) -> Repository {
+        mkdirp(&dir.join("ts"));
+        std::fs::write(dir.join("README.md"), "readme").unwrap();
+        std::fs::write(dir.join("ts/index.ts"), "export {}").unwrap();
+        git(dir, &["init", "-q"]);
+        git(dir, &["add", "-A"]);
+        git(dir, &["commit", "-q", "-m", "init"]);
+
+        let mut repo = Repository::default();
+        repo.url = format!("file://{}", dir.as_str());
+        repo.domain = "localhost".into();
+        repo.user = "regit".into();
+        repo.name = "templates".into();
+        repo.sub_dir = "ts".into();
+        repo._ref = "HEAD".into();
+        repo.mode = ValidModes::Git;
+        repo
+    }
+
+    #[tokio::test]
+    async fn clones_through_cache_in_git_mode() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = local_repo(&tmp.path().join("origin"));
+        let mut regit = Regit {
+            repo: repo.clone(),
+            cache: Cache::new_custom(tmp.path().join("cache").as_str()),
+            ..Default::default()
+        };
+        regit.options.cache = true;
+
+        let dest = tmp.path().join("dest");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(dest.join("index.ts")).unwrap(), "export {}");
+        assert!(!dest.join("README.md").exists(), "should only extract the subdirectory");
+
+        let repo_dir = tmp.path().join("cache").join(repo.cache_path());
+        assert!(repo_dir.join(MIRROR_DIR).is_dir(), "should keep a bare mirror");
+        let hash = regit.cache.get_cached_hash(repo_dir.as_str(), "HEAD")
+            .expect("should record resolved hash");
+        assert!(repo_dir.join(format!("{}.tar.gz", hash)).is_file(), "should cache the exported tarball");
+
+        let dest = tmp.path().join("dest2");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert!(dest.join("index.ts").is_file(), "should clone again from the existing mirror");
+    }
+
+    #[tokio::test]
+    async fn reports_unreachable_git_remote() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut repo = local_repo(&tmp.path().join("origin"));
+        repo.url = format!("file://{}", tmp.path().join("missing").as_str());
+        let mut regit = Regit {
+            repo,
+            cache: Cache::new_custom(tmp.path().join("cache").as_str()),
+            ..Default::default()
+        };
+        let err = regit.clone(tmp.path().join("dest").as_str()).await.unwrap_err();
+        assert!(err.contains("failed to mirror"), "{}", err);
+    }
+
+    #[test]
+    fn resolves_latest_tar_hash_without_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let origin = tmp.path().join("origin");
+        let mut repo = local_repo(&origin);
+        repo.mode = ValidModes::Tar;
+        let mut regit = Regit {
+            repo,
+            cache: Cache::new_custom(tmp.path().join("cache").as_str()),
+            ..Default::default()
+        };
+        let head = git(&origin, &["rev-parse", "HEAD"]);
+        assert_eq!(regit.latest_hash(), Ok(Some(head)));
+
+        let repo_dir = format!("{}/{}", regit.cache.dir(), regit.repo.cache_path());
+        regit.cache.update("HEAD", "0123456789abcdef0123456789abcdef01234567", &repo_dir);
+        regit.repo.url = format!("file://{}", tmp.path().join("missing").as_str());
+        assert!(regit.latest_hash().is_err(), "an unreachable upstream shouldn't look up to date");
+    }
+
+    #[test]
+    fn resolves_remote_refs_exactly() {
+        let tmp = tempfile::tempdir().unwrap();
+        let origin = tmp.path().join("origin");
+        local_repo(&origin);
+        git(&origin, &["branch", "-M", "main"]);
+        git(&origin, &["tag", "-a", "v1", "-m", "release"]);
+        let main = git(&origin, &["rev-parse", "HEAD"]);
+        fs::write(origin.join("README.md"), "feature").unwrap();
+        git(&origin, &["checkout", "-q", "-b", "feature/main"]);
+        git(&origin, &["commit", "-q", "-am", "feature"]);
+        let feature = git(&origin, &["rev-parse", "HEAD"]);
+
+        let urls = [format!("file://{}", origin.as_str())];
+        let resolve = |r: &str| Regit::resolve_remote_ref(&urls, r, &RegitOptions::default());
+        assert_eq!(resolve("main"), Ok(main.clone()), "shouldn't match 'feature/main'");
+        assert_eq!(resolve("feature/main"), Ok(feature.clone()));
+        assert_eq!(resolve("v1"), Ok(main.clone()), "annotated tags should resolve to their commit");
+        assert_eq!(resolve(&feature), Ok(feature.clone()));
+        assert!(resolve("missing").is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_git_history() {
+        let tmp = tempfile::tempdir().unwrap();
+        let origin = tmp.path().join("origin");
+        let repo = local_repo(&origin);
+        fs::write(origin.join("README.md"), "updated").unwrap();
+        git(&origin, &["commit", "-q", "-am", "readme"]);
+        let git_out = |dir: &Path, args: &[&str]| run_git(dir, args).unwrap();
+        let mut regit = Regit {
+            repo: repo.clone(),
+            cache: Cache::new_custom(tmp.path().join("cache").as_str()),
+            options: RegitOptions { keep_git: Some(KeepGit::Subtree), ..Default::default() },
+            ..Default::default()
+        };
+
+        let dest = tmp.path().join("subtree");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(git_out(&dest, &["log", "--format=%s"]), "init", "only commits touching the subdirectory");
+        assert_eq!(git_out(&dest, &["ls-files"]), "index.ts");
+        assert_eq!(git_out(&dest, &["status", "--porcelain"]), "?? .regit.json");
+        assert_eq!(git_out(&dest, &["remote", "get-url", "origin"]), repo.url());
+
+        regit.options.keep_git = Some(KeepGit::Full);
+        assert!(regit.clone(tmp.path().join("full").as_str()).await.is_err(), "full history needs the whole repository");
+
+        regit.repo.sub_dir = String::new();
+        let dest = tmp.path().join("full");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(git_out(&dest, &["log", "--format=%s"]), "readme\ninit");
+        assert_eq!(git_out(&dest, &["status", "--porcelain"]), "?? .regit.json");
+        assert!(git_out(&dest, &["rev-parse", "--abbrev-ref", "@{upstream}"]).starts_with("origin/"));
+    }
+
+    #[tokio::test]
+    async fn stashes_destination_before_forced_clone() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut regit = Regit {
+            repo: local_repo(&tmp.path().join("origin")),
+            cache: Cache::new_custom(tmp.path().join("cache").as_str()),
+            ..Default::default()
+        };
+        regit.options.conflict = ConflictStrategy::Overwrite;
+
+        let dest = tmp.path().join("dest");
+        mkdirp(&dest);
+        fs::write(dest.join("index.ts"), "mine").unwrap();
+        regit.clone(dest.as_str()).await.unwrap();
+
+        assert!(regit.options.has_stashed);
+        assert_eq!(fs::read_to_string(dest.join("index.ts")).unwrap(), "export {}");
+        assert_eq!(regit.cache.stashes()[0].entries, vec!["index.ts"]);
+    }
+
+    #[tokio::test]
+    async fn plans_without_writing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut regit = Regit {
+            repo: local_repo(&tmp.path().join("origin")),
+            cache: Cache::new_custom(tmp.path().join("cache").as_str()),
+            ..Default::default()
+        };
+        let dest = tmp.path().join("dest");
+        regit.options.conflict = ConflictStrategy::Merge;
+
+        regit.options.dry_run = true;
+        regit.clone(dest.as_str()).await.unwrap();
+        assert!(!dest.exists(), "dry run shouldn't create the destination");
+
+        regit.options.dry_run = false;
+        regit.clone(dest.as_str()).await.unwrap();
+        fs::write(dest.join("index.ts"), "mine").unwrap();
+
+        let plan = regit.plan(dest.as_str()).unwrap();
+        assert!(plan.cached);
+        assert_eq!(plan._ref, "HEAD");
+        assert_eq!(plan.hash.len(), 40);
+        assert_eq!(plan.files, Some(vec![("index.ts".to_owned(), FileOutcome::Skipped)]));
+
+        regit.options.conflict = ConflictStrategy::Abort;
+        let plan = regit.plan(dest.as_str()).unwrap();
+        assert_eq!(plan.files, Some(vec![("index.ts".to_owned(), FileOutcome::Conflict)]));
+        assert_eq!(fs::read_to_string(dest.join("index.ts")).unwrap(), "mine");
+    }
+
+    const COMMIT_TIME: u64 = 1_600_000_000;
+
+    fn fixture(dir: &Path) -> std::path::PathBuf {
+        let file = dir.join("fixture.tar.gz");
+        let gz = flate2::write::GzEncoder::new(fs::File::create(&file).unwrap(), flate2::Compression::fast());
+        let mut builder = tar::Builder::new(gz);
+        let entries = [
+            ("repo-abc/a.txt", "new a", 0o644, COMMIT_TIME),
+            ("repo-abc/b.txt", "new b", 0o644, COMMIT_TIME),
+            ("repo-abc/bin/", "", 0o775, COMMIT_TIME),
+            ("repo-abc/bin/run.sh", "#!/bin/sh", 0o4775, COMMIT_TIME - 60),
+        ];
+        for (path, contents, mode, mtime) in entries {
+            let mut header = tar::Header::new_gnu();
+            if path.ends_with('/') { header.set_entry_type(tar::EntryType::Directory) }
+            header.set_size(contents.len() as u64);
+            header.set_mode(mode);
+            header.set_mtime(mtime);
+            header.set_uid(4242);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        file
+    }
+
+    fn untar_over_existing(strategy: ConflictStrategy, answer: ConflictStrategy) -> (tempfile::TempDir, Result<Vec<(String, FileOutcome)>, String>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = fixture(tmp.path());
+        let dest = tmp.path().join("dest");
+        mkdirp(&dest);
+        fs::write(dest.join("a.txt"), "old a").unwrap();
+
+        let opts = ExtractOptions { strategy, ..(&RegitOptions::default()).into() };
+        let result = Regit::untar(&file, &dest, "repo-abc/", &opts, &mut |_| answer);
+        (tmp, result)
+    }
+
+    #[test]
+    fn aborts_on_existing_file() {
+        let (tmp, result) = untar_over_existing(ConflictStrategy::Abort, ConflictStrategy::Abort);
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(tmp.path().join("dest/a.txt")).unwrap(), "old a");
+    }
+
+    #[test]
+    fn merges_only_new_files() {
+        let (tmp, result) = untar_over_existing(ConflictStrategy::Merge, ConflictStrategy::Abort);
+        assert_eq!(result.unwrap(), vec![
+            ("a.txt".to_owned(), FileOutcome::Skipped),
+            ("b.txt".to_owned(), FileOutcome::Created),
+            ("bin/run.sh".to_owned(), FileOutcome::Created),
+        ]);
+        assert_eq!(fs::read_to_string(tmp.path().join("dest/a.txt")).unwrap(), "old a");
+        assert_eq!(fs::read_to_string(tmp.path().join("dest/b.txt")).unwrap(), "new b");
+    }
+
+    #[test]
+    fn overwrites_existing_files() {
+        let (tmp, result) = untar_over_existing(ConflictStrategy::Overwrite, ConflictStrategy::Abort);
+        assert_eq!(result.unwrap()[0], ("a.txt".to_owned(), FileOutcome::Overwritten));
+        assert_eq!(fs::read_to_string(tmp.path().join("dest/a.txt")).unwrap(), "new a");
+    }
+
+    #[test]
+    fn backs_up_existing_files() {
+        let (tmp, result) = untar_over_existing(ConflictStrategy::Backup, ConflictStrategy::Abort);
+        assert_eq!(result.unwrap()[0], ("a.txt".to_owned(), FileOutcome::BackedUp("a.txt.bak".into())));
+        assert_eq!(fs::read_to_string(tmp.path().join("dest/a.txt")).unwrap(), "new a");
+        assert_eq!(fs::read_to_string(tmp.path().join("dest/a.txt.bak")).unwrap(), "old a");
+    }
+
+    #[test]
+    fn asks_for_each_clashing_file() {
+        let (tmp, result) = untar_over_existing(ConflictStrategy::Interactive, ConflictStrategy::Merge);
+        assert_eq!(result.unwrap()[0], ("a.txt".to_owned(), FileOutcome::Skipped));
+        assert_eq!(fs::read_to_string(tmp.path().join("dest/a.txt")).unwrap(), "old a");
+
+        let (_, result) = untar_over_existing(ConflictStrategy::Interactive, ConflictStrategy::Abort);
+        assert!(result.is_err());
+    }
+
+    fn untar_with(mtime: MtimePolicy, umask: u32) -> tempfile::TempDir {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = fixture(tmp.path());
+        let opts = ExtractOptions { mtime, umask, ..(&RegitOptions::default()).into() };
+        Regit::untar(&file, &tmp.path().join("dest"), "repo-abc/", &opts, &mut |_| ConflictStrategy::Abort).unwrap();
+        tmp
+    }
+
+    fn mtime_of(path: &Path) -> u64 {
+        let mtime = fs::metadata(path).unwrap().modified().unwrap();
+        mtime.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_only_executable_bit_under_umask() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let tmp = untar_with(MtimePolicy::Preserve, 0o027);
+        let dest = tmp.path().join("dest");
+        let mode = |path: &str| fs::metadata(dest.join(path)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("a.txt"), 0o640);
+        assert_eq!(mode("bin/run.sh"), 0o750, "setuid and group write should be dropped");
+        assert_eq!(mode("bin"), 0o750);
+
+        let uid = fs::metadata(tmp.path()).unwrap().uid();
+        assert_eq!(fs::metadata(dest.join("a.txt")).unwrap().uid(), uid, "ownership shouldn't be applied");
+    }
+
+    #[test]
+    fn applies_mtime_policy() {
+        let tmp = untar_with(MtimePolicy::Preserve, 0o022);
+        assert_eq!(mtime_of(&tmp.path().join("dest/a.txt")), COMMIT_TIME);
+        assert_eq!(mtime_of(&tmp.path().join("dest/bin/run.sh")), COMMIT_TIME - 60);
+
+        let tmp = untar_with(MtimePolicy::Commit, 0o022);
+        assert_eq!(mtime_of(&tmp.path().join("dest/bin/run.sh")), COMMIT_TIME);
+        assert_eq!(mtime_of(&tmp.path().join("dest/bin")), COMMIT_TIME);
+
+        let now = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let tmp = untar_with(MtimePolicy::Now, 0o022);
+        assert!(mtime_of(&tmp.path().join("dest/a.txt")) >= now);
+    }
+
+    fn tar_bytes() -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, contents) in [("repo-abc/a.txt", "a"), ("repo-abc/sub/b.txt", "b"), ("other/c.txt", "c")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn zip_bytes(entries: &[(&str, &str)]) -> Vec<u8> {
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        for (path, contents) in entries {
+            zip.start_file(*path, zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn extracts_every_format() {
+        use std::io::Write;
+        let tar = tar_bytes();
+        let compress = |mut w: Box<dyn Write>| { w.write_all(&tar).unwrap(); };
+        let tmp = tempfile::tempdir().unwrap();
+        let file = |ext: &str| tmp.path().join(format!("archive.{}", ext));
+
+        fs::write(file("tar"), &tar).unwrap();
+        compress(Box::new(flate2::write::GzEncoder::new(fs::File::create(file("tar.gz")).unwrap(), flate2::Compression::fast())));
+        compress(Box::new(bzip2::write::BzEncoder::new(fs::File::create(file("tar.bz2")).unwrap(), bzip2::Compression::fast())));
+        compress(Box::new(xz2::write::XzEncoder::new(fs::File::create(file("tar.xz")).unwrap(), 1)));
+        compress(Box::new(zstd::Encoder::new(fs::File::create(file("tar.zst")).unwrap(), 1).unwrap().auto_finish()));
+        fs::write(file("zip"), zip_bytes(&[("repo-abc/a.txt", "a"), ("repo-abc/sub/b.txt", "b"), ("other/c.txt", "c")])).unwrap();
+
+        for format in ArchiveFormat::ALL {
+            let archive = file(format.extension());
+            assert_eq!(ArchiveFormat::detect(&archive).unwrap(), format);
+
+            let dest = tmp.path().join(format!("dest-{}", format.extension()));
+            let opts = ExtractOptions::from(&RegitOptions::default());
+            let outcomes = Regit::untar(&archive, &dest, "repo-abc/", &opts, &mut |_| ConflictStrategy::Abort).unwrap();
+            assert_eq!(outcomes.len(), 2, "{:?} should only extract the subdirectory", format);
+            assert_eq!(fs::read_to_string(dest.join("sub/b.txt")).unwrap(), "b");
+            assert!(!dest.join("c.txt").exists());
+        }
+    }
+
+    #[test]
+    fn refuses_paths_outside_destination() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("evil.zip");
+        fs::write(&archive, zip_bytes(&[("repo-abc/../../evil.txt", "evil"), ("repo-abc/ok.txt", "ok")])).unwrap();
+
+        let dest = tmp.path().join("a/dest");
+        let opts = ExtractOptions::from(&RegitOptions::default());
+        let outcomes = Regit::untar(&archive, &dest, "repo-abc/", &opts, &mut |_| ConflictStrategy::Abort).unwrap();
+        assert_eq!(outcomes, vec![("ok.txt".to_owned(), FileOutcome::Created)]);
+        assert!(!tmp.path().join("evil.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_replacing_files_through_linked_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (dest, outside) = (tmp.path().join("dest"), tmp.path().join("outside"));
+        fs::create_dir_all(&dest).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("x.txt"), "mine").unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join("link")).unwrap();
+
+        let written = Regit::write_entry(&dest.join("link/x.txt"), &dest, &EntryKind::File, &mut &b"theirs"[..]);
+        assert!(written.is_err());
+        assert_eq!(fs::read_to_string(outside.join("x.txt")).unwrap(), "mine");
+    }
+
+    fn archive_regit(src: &str, cache: &Path) -> Regit {
+        Regit {
+            repo: Repository::parse(src, &Config::default()),
+            cache: Cache::new_custom(cache.as_str()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn clones_from_local_archive_with_subdir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("tpl.zip");
+        fs::write(&archive, zip_bytes(&[("tpl-1.0/api/main.rs", "fn main() {}"), ("tpl-1.0/web/index.html", "")])).unwrap();
+
+        let src = format!("file://{}//api", archive.as_str());
+        let mut regit = archive_regit(&src, &tmp.path().join("cache"));
+        let dest = tmp.path().join("dest");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("main.rs")).unwrap(), "fn main() {}");
+        assert!(!dest.join("index.html").exists());
+
+        regit.options.sha256 = Some("0".repeat(64));
+        regit.options.conflict = ConflictStrategy::Overwrite;
+        assert!(regit.clone(dest.as_str()).await.unwrap_err().contains("SHA-256 mismatch"));
+    }
+
+    #[tokio::test]
+    async fn fetches_remote_archive_into_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let body = zip_bytes(&[("a.txt", "a"), ("sub/b.txt", "b")]);
+        let hash = crate::util::sha256_hex(&body);
+        let base = crate::util::serve(vec![("/tpl.zip".into(), 200, body)]);
+
+        let mut regit = archive_regit(&format!("{}/tpl.zip", base), &tmp.path().join("cache"));
+        regit.options.sha256 = Some(hash.to_uppercase());
+        let dest = tmp.path().join("dest");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("sub/b.txt")).unwrap(), "b");
+
+        let repo_dir = tmp.path().join("cache").join(regit.repo.cache_path());
+        assert_eq!(regit.cache.get_cached_hash(repo_dir.as_str(), ARCHIVE_REF), Some(hash.clone()));
+        assert!(repo_dir.join(format!("{}.zip", hash)).is_file(), "should cache the archive in its own format");
+    }
+
+    #[tokio::test]
+    async fn updates_scaffold_with_three_way_merge() {
+        let tmp = tempfile::tempdir().unwrap();
+        let origin = tmp.path().join("origin");
+        mkdirp(&origin.join("ts"));
+        fs::write(origin.join("ts/lib.ts"), "a\nb\nc\nd\n").unwrap();
+        git(&origin, &["init", "-q"]);
+        git(&origin, &["add", "-A"]);
+        git(&origin, &["commit", "-q", "-m", "init"]);
+
+        let options = RegitOptions { cache_dir: Some(tmp.path().join("cache").as_str().into()), ..Default::default() };
+        let src = format!("{}/ts#HEAD", origin.as_str());
+        let dest = tmp.path().join("dest");
+        let mut regit = Regit::new(&src, options.clone());
+        regit.clone(dest.as_str()).await.unwrap();
+        let provenance = Provenance::load(&dest).unwrap();
+        assert_eq!((provenance.source.as_str(), provenance.commit.as_deref()), (src.as_str(), regit.hash()));
+        assert_eq!((provenance.sub_dir.as_str(), provenance._ref.as_str()), ("ts", "HEAD"));
+        assert_eq!(provenance.version, env!("CARGO_PKG_VERSION"));
+        assert!(provenance.archive_sha256.is_some());
+
+        fs::write(dest.join("lib.ts"), "A\nb\nc\nd\n").unwrap();
+        fs::write(origin.join("ts/lib.ts"), "a\nb\nc\nD\n").unwrap();
+        fs::write(origin.join("ts/new.ts"), "new").unwrap();
+        git(&origin, &["add", "-A"]);
+        git(&origin, &["commit", "-q", "-m", "update"]);
+
+        let status = Regit::status(dest.as_str(), options.clone()).unwrap();
+        assert!(status.is_behind(), "upstream moved: {:?}", status);
+        let local = Regit::diff(dest.as_str(), options.clone(), false).await.unwrap();
+        assert!(local.contains("-a\n+A\n"), "{}", local);
+        let upstream = Regit::diff(dest.as_str(), options.clone(), true).await.unwrap();
+        assert!(upstream.contains("-d\n+D\n") && upstream.contains("+++ b/new.ts"), "{}", upstream);
+
+        Regit::update(dest.as_str(), options.clone(), false).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("lib.ts")).unwrap(), "A\nb\nc\nD\n");
+        assert_eq!(fs::read_to_string(dest.join("new.ts")).unwrap(), "new");
+        let updated = Provenance::load(&dest).unwrap();
+        assert_ne!(updated.commit, provenance.commit, "should record the new commit");
+
+        Regit::update(dest.as_str(), options.clone(), false).await.unwrap();
+        assert_eq!(Provenance::load(&dest).unwrap(), updated);
+        assert!(!Regit::status(dest.as_str(), options).unwrap().is_behind());
+    }
+
+    #[tokio::test]
+    async fn reapplies_patches_on_update() {
+        let tmp = tempfile::tempdir().unwrap();
+        let origin = tmp.path().join("origin");
+        mkdirp(&origin);
+        fs::write(origin.join("config.toml"), "port = 80\nhost = \"a\"\n").unwrap();
+        git(&origin, &["init", "-q"]);
+        git(&origin, &["add", "-A"]);
+        git(&origin, &["commit", "-q", "-m", "init"]);
+
+        let patch = tmp.path().join("port.diff");
+        fs::write(&patch, "--- a/config.toml\n+++ b/config.toml\n@@ -1,2 +1,2 @@\n-port = 80\n+port = 8080\n host = \"a\"\n").unwrap();
+        let options = RegitOptions { cache_dir: Some(tmp.path().join("cache").as_str().into()), ..Default::default() };
+        let dest = tmp.path().join("dest");
+        let src = format!("{}#HEAD", origin.as_str());
+        let mut regit = Regit::new(&src, RegitOptions { patches: vec![patch.as_str().into()], ..options.clone() });
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("config.toml")).unwrap(), "port = 8080\nhost = \"a\"\n");
+        assert_eq!(Provenance::load(&dest).unwrap().patches, vec![patch.as_str().to_owned()]);
+        assert_eq!(Regit::diff(dest.as_str(), options.clone(), false).await.unwrap(), "", "patched lines aren't local edits");
+
+        fs::write(origin.join("config.toml"), "# config\nport = 80\nhost = \"b\"\n").unwrap();
+        git(&origin, &["commit", "-q", "-am", "update"]);
+        Regit::update(dest.as_str(), options.clone(), false).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("config.toml")).unwrap(), "# config\nport = 8080\nhost = \"b\"\n");
+
+        fs::write(origin.join("config.toml"), "port = 443\n").unwrap();
+        git(&origin, &["commit", "-q", "-am", "rewrite"]);
+        assert!(Regit::update(dest.as_str(), options, false).await.is_err(), "rejected hunks should fail the update");
+        assert!(dest.join("config.toml.rej").exists());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_mirror() {
+        let tmp = tempfile::tempdir().unwrap();
+        let body = zip_bytes(&[("a.txt", "a")]);
+        let hash = crate::util::sha256_hex(&body);
+        let base = crate::util::serve(vec![("/github/tpl.zip".into(), 200, body)]);
+
+        let mut regit = archive_regit("https://github.com/acme/tpl.zip", &tmp.path().join("cache"));
+        let cache_path = regit.repo.cache_path();
+        regit.repo.mirrors = vec![format!("{}/broken/tpl.zip", base), format!("{}/github/tpl.zip", base)];
+        let dest = tmp.path().join("dest");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "a");
+
+        assert_eq!(regit.repo.cache_path(), cache_path, "mirrors shouldn't change the cache key");
+        let repo_dir = tmp.path().join("cache").join(cache_path);
+        assert_eq!(regit.cache.get_cached_hash(repo_dir.as_str(), ARCHIVE_REF), Some(hash));
+    }
+
+    #[tokio::test]
+    async fn copies_local_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("templates/api");
+        mkdirp(&src.join(".git"));
+        mkdirp(&src.join("src"));
+        fs::write(src.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(src.join("Cargo.toml"), "[package]").unwrap();
+
+        let mut regit = archive_regit(src.as_str(), &tmp.path().join("cache"));
+        let dest = tmp.path().join("dest");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("src/main.rs")).unwrap(), "fn main() {}");
+        assert!(!dest.join(".git").exists());
+
+        regit.options.conflict = ConflictStrategy::Merge;
+        assert!(regit.clone(src.join("src").as_str()).await.is_err(), "shouldn't copy into itself");
+    }
+
+    #[test]
+    fn packs_selected_files_reproducibly() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("tpl.zip");
+        fs::write(&file, zip_bytes(&[
+            ("tpl-1.0/api/src/main.rs", "fn main() {}"),
+            ("tpl-1.0/api/README.md", ""),
+            ("tpl-1.0/api/Cargo.toml", "[package]"),
+            ("tpl-1.0/web/index.html", ""),
+        ])).unwrap();
+        let opts = ExtractOptions { filter: Filter::new(&[], &["*.md".into()]).unwrap(), ..(&RegitOptions::default()).into() };
+        let pack = |format| {
+            let output = Output { format, path: tmp.path().join("out").as_str().into() };
+            Regit::pack(&file, "tpl-1.0/api/", &opts, &output).unwrap();
+            fs::read(&output.path).unwrap()
         };
-        if count_unpacked == 0 { warn!("No files unpacked"); }
-        else { success!(&format!("Unpacked {} files", count_unpacked)); }
-        // }
+
+        let tar = pack(OutputFormat::Tar);
+        assert_eq!(tar, pack(OutputFormat::Tar), "the same files give the same bytes");
+        let entries: Vec<_> = tar::Archive::new(&tar[..]).entries().unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.path().unwrap().to_string_lossy().into_owned(), entry.header().mtime().unwrap())
+            })
+            .collect();
+        // Zip entries without a timestamp date from 1980-01-01.
+        assert_eq!(entries, vec![("Cargo.toml".to_owned(), 315_532_800), ("src/main.rs".to_owned(), 315_532_800)]);
+
+        assert_eq!(ArchiveFormat::detect_bytes(&pack(OutputFormat::TarGz)), Some(ArchiveFormat::TarGz));
+        fs::write(tmp.path().join("out.zip"), pack(OutputFormat::Zip)).unwrap();
+        let mut paths = vec![];
+        archive::open(&tmp.path().join("out.zip")).unwrap().for_each_entry(&mut |entry, _| {
+            paths.push(entry.path.clone());
+            Ok(())
+        }).unwrap();
+        assert_eq!(paths, ["Cargo.toml", "src/main.rs"]);
+    }
+
+    #[tokio::test]
+    async fn initializes_git_repository() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("tpl");
+        mkdirp(&src);
+        fs::write(src.join("README.md"), "readme").unwrap();
+        let log = |dir: &Path, format: &str| git(dir, &["log", "-1", &format!("--format={}", format)]);
+
+        let options = RegitOptions {
+            git_init: Some(GitInit {
+                branch: Some("trunk".into()),
+                message: "Scaffold".into(),
+                identity: Some(("regit".into(), "regit@localhost".into())),
+            }),
+            ..Default::default()
+        };
+        let mut regit = Regit::with_cache(src.as_str(), options, Cache::new_custom(tmp.path().join("cache").as_str()));
+        let dest = tmp.path().join("app");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(log(&dest, "%D"), "HEAD -> trunk");
+        assert_eq!(log(&dest, "%B"), format!("Scaffold\n\nTemplate: {}", src.as_str()));
+        assert_eq!(log(&dest, "%an <%ce>"), "regit <regit@localhost>");
+
+        let nested = dest.join("vendor/tpl");
+        regit.clone(nested.as_str()).await.unwrap();
+        assert_eq!(git(&dest, &["status", "--porcelain"]), "A  vendor/tpl/.regit.json\nA  vendor/tpl/README.md");
+        assert!(!nested.join(".git").exists(), "shouldn't nest a repository");
+
+        regit.options.git_init = Some(GitInit { branch: Some("bad..name".into()), ..Default::default() });
+        assert!(regit.clone(tmp.path().join("other").as_str()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn renders_template_variables() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("template");
+        mkdirp(&src.join("src/__crate_name__"));
+        fs::write(src.join("src/__crate_name__/lib.rs"), "// {{ project_name }} by {{author}}").unwrap();
+        fs::write(src.join("logo.bin"), b"\0{{project_name}}").unwrap();
+        fs::write(src.join(TEMPLATE_FILE), concat!(
+            "[variables.project_name]\npattern = \"^[a-z-]+$\"\n",
+            "[variables.crate_name]\ndefault = \"core\"\n",
+            "[variables.author]\ndefault = \"me\"\n",
+        )).unwrap();
+
+        let clone = |vars: &[(&str, &str)], dest: &str| {
+            let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            let mut regit = archive_regit(src.as_str(), &tmp.path().join("cache"));
+            regit.options.template = Some(TemplateVars { vars, prompt: false });
+            let dest = tmp.path().join(dest);
+            async move { regit.clone(dest.as_str()).await.map(|_| dest) }
+        };
+
+        assert!(clone(&[], "missing").await.is_err(), "required variables should be asked for");
+        assert!(clone(&[("project_name", "Demo")], "invalid").await.is_err(), "values should be validated");
+
+        let dest = clone(&[("project_name", "demo"), ("author", "ann")], "dest").await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("src/core/lib.rs")).unwrap(), "// demo by ann");
+        assert_eq!(fs::read(dest.join("logo.bin")).unwrap(), b"\0{{project_name}}", "binary files should be left alone");
+        assert!(!dest.join(TEMPLATE_FILE).exists());
+        assert_eq!(Provenance::load(&dest).unwrap().vars.get("crate_name").map(String::as_str), Some("core"));
+    }
+
+    #[tokio::test]
+    async fn composes_layers_in_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (base, overlay) = (tmp.path().join("base"), tmp.path().join("overlay"));
+        for (path, contents) in [
+            (base.join("app.toml"), "base"),
+            (base.join("README.md"), "base"),
+            (base.join("src/main.rs"), "fn main() {}"),
+            (overlay.join("auth/app.toml"), "auth"),
+            (overlay.join("auth/src/auth.rs"), "// auth"),
+            (overlay.join("auth/NOTES.md"), "notes"),
+            (overlay.join("docker/Dockerfile"), "FROM scratch"),
+        ] {
+            mkdirp(path.parent().unwrap());
+            fs::write(path, contents).unwrap();
+        }
+        let layers = [
+            Layer { src: base.as_str().into(), ..Default::default() },
+            Layer { src: overlay.as_str().into(), sub_dir: Some("auth".into()), exclude: vec!["*.md".into()], ..Default::default() },
+        ];
+        let cache = Cache::at(Some(tmp.path().join("cache").as_str())).load();
+
+        let dest = tmp.path().join("dest");
+        Regit::compose(&layers, &[], dest.as_str(), RegitOptions::default(), LayerConflict::Precedence, cache.clone()).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("app.toml")).unwrap(), "auth", "later layers should win");
+        assert_eq!(fs::read_to_string(dest.join("README.md")).unwrap(), "base");
+        assert!(dest.join("src/main.rs").exists() && dest.join("src/auth.rs").exists());
+        assert!(!dest.join("NOTES.md").exists() && !dest.join("Dockerfile").exists());
+
+        let err = Regit::compose(&layers, &[], tmp.path().join("strict").as_str(), RegitOptions::default(), LayerConflict::Error, cache.clone())
+            .await
+            .unwrap_err();
+        assert!(err.contains("app.toml"), "{}", err);
+        assert!(!tmp.path().join("strict").exists(), "nothing should be written on conflicts");
+
+        let out = tmp.path().join("out.tar");
+        let options = RegitOptions { output: Some(Output { format: OutputFormat::Tar, path: out.as_str().into() }), ..Default::default() };
+        Regit::compose(&layers, &[], ".", options, LayerConflict::Precedence, cache).await.unwrap();
+        let mut packed = vec![];
+        archive::open(&out).unwrap().for_each_entry(&mut |entry, _| {
+            if entry.kind != EntryKind::Dir { packed.push(entry.path.clone()) }
+            Ok(())
+        }).unwrap();
+        packed.sort();
+        assert_eq!(packed, ["README.md", "app.toml", "src/auth.rs", "src/main.rs"], "both layers should be packed");
+    }
+
+    #[tokio::test]
+    async fn plans_layers_without_fetching() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("base");
+        mkdirp(&base);
+        fs::write(base.join("app.toml"), "base").unwrap();
+        let layers = [
+            Layer { src: base.as_str().into(), ..Default::default() },
+            // Nothing listens there, a download would fail.
+            Layer { src: "http://127.0.0.1:9/overlay.tar.gz".into(), ..Default::default() },
+        ];
+        let cache = Cache::at(Some(tmp.path().join("cache").as_str())).load();
+        let dest = tmp.path().join("dest");
+        let options = RegitOptions { dry_run: true, ..Default::default() };
+
+        let hashes = Regit::compose(&layers, &[], dest.as_str(), options, LayerConflict::Error, cache).await.unwrap();
+        assert_eq!(hashes, [None, None]);
+        assert!(!dest.exists(), "a dry run shouldn't write anything");
+    }
+
+    #[tokio::test]
+    async fn runs_template_actions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (base, shared) = (tmp.path().join("base"), tmp.path().join("shared"));
+        mkdirp(&base);
+        mkdirp(&shared);
+        fs::write(base.join("LICENSE"), "").unwrap();
+        fs::write(base.join(".gitignore"), "target/\n").unwrap();
+        fs::write(shared.join("ci.yml"), "on: push").unwrap();
+        fs::write(base.join(ACTIONS_FILE), format!(r#"[
+            {{ "action": "clone", "src": "{}", "dest": ".github" }},
+            {{ "action": "remove", "files": "LICENSE" }},
+            {{ "action": "append", "file": ".gitignore", "contents": "dist/\n" }},
+            {{ "action": "shell", "command": "touch hooked" }}
+        ]"#, shared.as_str())).unwrap();
+
+        let mut regit = archive_regit(base.as_str(), &tmp.path().join("cache"));
+        let dest = tmp.path().join("dest");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join(".github/ci.yml")).unwrap(), "on: push");
+        assert!(!dest.join(".github").join(crate::provenance::PROVENANCE_FILE).exists());
+        assert_eq!(fs::read_to_string(dest.join(".gitignore")).unwrap(), "target/\ndist/\n");
+        assert!(!dest.join("LICENSE").exists());
+        assert!(!dest.join(ACTIONS_FILE).exists(), "the action file should be removed");
+        assert!(!dest.join("hooked").exists(), "hooks shouldn't run unconfirmed");
+
+        fs::write(shared.join(ACTIONS_FILE), format!(r#"[{{ "action": "clone", "src": "{}", "dest": "again" }}]"#, shared.as_str())).unwrap();
+        let mut regit = archive_regit(shared.as_str(), &tmp.path().join("cache"));
+        let err = regit.clone(tmp.path().join("loop").as_str()).await.unwrap_err();
+        assert!(err.contains("levels deep"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn exports_local_repository_at_ref() {
+        let tmp = tempfile::tempdir().unwrap();
+        let origin = tmp.path().join("origin");
+        local_repo(&origin);
+        git(&origin, &["tag", "v1"]);
+        fs::write(origin.join("ts/index.ts"), "export const v2 = true").unwrap();
+        git(&origin, &["commit", "-q", "-am", "v2"]);
+
+        let mut regit = archive_regit(&format!("file://{}/ts#v1", origin.as_str()), &tmp.path().join("cache"));
+        assert_eq!(regit.repo.mode, ValidModes::Local);
+        let dest = tmp.path().join("dest");
+        regit.clone(dest.as_str()).await.unwrap();
+        assert_eq!(fs::read_to_string(dest.join("index.ts")).unwrap(), "export {}");
     }
 }
@@ -0,0 +1,410 @@
+use std::{collections::BTreeMap, fs, io::{self, Read, Seek, Write}, path::{Path, PathBuf}};
+
+use crate::{options::OutputFormat, util::civil_utc};
+
+/// Archive formats regit can read, detected by their magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarBz2,
+    TarXz,
+    TarZst,
+    Zip,
+}
+impl ArchiveFormat {
+    pub const ALL: [ArchiveFormat; 6] = [
+        ArchiveFormat::TarGz,
+        ArchiveFormat::TarBz2,
+        ArchiveFormat::TarXz,
+        ArchiveFormat::TarZst,
+        ArchiveFormat::Zip,
+        ArchiveFormat::Tar,
+    ];
+
+    pub fn detect(file: &Path) -> Result<Self, String> {
+        let mut magic = Vec::with_capacity(262);
+        fs::File::open(file)
+            .and_then(|f| f.take(262).read_to_end(&mut magic))
+            .map_err(|e| format!("can't read '{}': {}", file.display(), e))?;
+        Self::detect_bytes(&magic)
+            .ok_or_else(|| format!("'{}' isn't a supported archive", file.display()))
+    }
+
+    pub fn detect_bytes(magic: &[u8]) -> Option<Self> {
+        match magic {
+            [0x1f, 0x8b, ..] => Some(ArchiveFormat::TarGz),
+            [b'B', b'Z', b'h', ..] => Some(ArchiveFormat::TarBz2),
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Some(ArchiveFormat::TarXz),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(ArchiveFormat::TarZst),
+            [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => Some(ArchiveFormat::Zip),
+            _ if magic.get(257..262) == Some(b"ustar") => Some(ArchiveFormat::Tar),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarBz2 => "tar.bz2",
+            ArchiveFormat::TarXz => "tar.xz",
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+/// Archive file for `hash` in `repo_dir`, in whichever format was cached.
+pub fn find_cached(repo_dir: &Path, hash: &str) -> Option<PathBuf> {
+    ArchiveFormat::ALL.iter()
+        .map(|format| repo_dir.join(format!("{}.{}", hash, format.extension())))
+        .find(|file| file.is_file())
+}
+
+/// The single top-level directory every entry of `file` lives in, with a
+/// trailing `/`, or an empty string when entries don't share one.
+pub fn common_prefix(file: &Path) -> Result<String, String> {
+    let mut prefix: Option<String> = None;
+    let mut shared = true;
+    open(file)?.for_each_entry(&mut |entry, _| {
+        let Some((top, _)) = entry.path.split_once('/') else {
+            // A top-level file, or the top-level directory entry itself.
+            shared &= entry.kind == EntryKind::Dir;
+            return Ok(());
+        };
+        match &prefix {
+            Some(p) => shared &= p == top,
+            None => prefix = Some(top.to_owned()),
+        }
+        Ok(())
+    })?;
+    Ok(prefix.filter(|_| shared).map_or_else(String::new, |p| p + "/"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink(String),
+}
+
+/// Format independent view of an archive entry.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub mtime: u64,
+}
+
+pub type EntryVisitor<'a> = dyn FnMut(&Entry, &mut dyn Read) -> Result<(), String> + 'a;
+
+/// Streams archive entries in archive order, handing each one to a visitor
+/// together with a reader for its contents.
+pub trait ArchiveReader {
+    fn for_each_entry(&mut self, visit: &mut EntryVisitor) -> Result<(), String>;
+}
+
+/// Opens `file` with the reader matching its detected format. Directories
+/// are read as if they were an archive of their contents.
+pub fn open(file: &Path) -> Result<Box<dyn ArchiveReader>, String> {
+    if file.is_dir() {
+        return Ok(Box::new(DirReader(file.to_owned())));
+    }
+    let format = ArchiveFormat::detect(file)?;
+    let f = fs::File::open(file).map_err(|e| format!("error opening file: {}", e))?;
+    let err = |e: std::io::Error| e.to_string();
+    Ok(match format {
+        ArchiveFormat::Tar => Box::new(TarReader(tar::Archive::new(f))),
+        ArchiveFormat::TarGz => Box::new(TarReader(tar::Archive::new(flate2::read::GzDecoder::new(f)))),
+        ArchiveFormat::TarBz2 => Box::new(TarReader(tar::Archive::new(bzip2::read::BzDecoder::new(f)))),
+        ArchiveFormat::TarXz => Box::new(TarReader(tar::Archive::new(xz2::read::XzDecoder::new(f)))),
+        ArchiveFormat::TarZst => Box::new(TarReader(tar::Archive::new(zstd::Decoder::new(f).map_err(err)?))),
+        ArchiveFormat::Zip => Box::new(ZipReader(zip::ZipArchive::new(f).map_err(|e| e.to_string())?)),
+    })
+}
+
+struct TarReader<R: Read>(tar::Archive<R>);
+
+impl<R: Read> ArchiveReader for TarReader<R> {
+    fn for_each_entry(&mut self, visit: &mut EntryVisitor) -> Result<(), String> {
+        for entry in self.0.entries().map_err(|e| e.to_string())? {
+            let mut entry = entry.map_err(|e| e.to_string())?;
+            let header = entry.header();
+            let kind = match header.entry_type() {
+                t if t.is_dir() => EntryKind::Dir,
+                t if t.is_file() => EntryKind::File,
+                t if t.is_symlink() => {
+                    let target = entry.link_name().map_err(|e| e.to_string())?.unwrap_or_default();
+                    EntryKind::Symlink(target.to_string_lossy().into())
+                },
+                // Pax headers, hard links and devices aren't template content.
+                _ => continue,
+            };
+            let meta = Entry {
+                path: entry.path().map_err(|e| e.to_string())?.to_string_lossy().into(),
+                kind,
+                mode: header.mode().unwrap_or(0o644),
+                mtime: header.mtime().unwrap_or(0),
+            };
+            visit(&meta, &mut entry)?;
+        }
+        Ok(())
+    }
+}
+
+struct ZipReader<R: Read + Seek>(zip::ZipArchive<R>);
+
+impl<R: Read + Seek> ArchiveReader for ZipReader<R> {
+    fn for_each_entry(&mut self, visit: &mut EntryVisitor) -> Result<(), String> {
+        for i in 0..self.0.len() {
+            let mut file = self.0.by_index(i).map_err(|e| e.to_string())?;
+            let mode = file.unix_mode().unwrap_or(if file.is_dir() { 0o755 } else { 0o644 });
+            let kind = match mode & 0o170000 {
+                _ if file.is_dir() => EntryKind::Dir,
+                0o120000 => {
+                    let mut target = String::new();
+                    file.read_to_string(&mut target).map_err(|e| e.to_string())?;
+                    EntryKind::Symlink(target)
+                },
+                _ => EntryKind::File,
+            };
+            let meta = Entry {
+                path: file.name().to_owned(),
+                kind,
+                mode: mode & 0o7777,
+                mtime: file.last_modified().map_or(0, dos_to_unix),
+            };
+            visit(&meta, &mut file)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a directory tree in sorted order, skipping `.git`.
+struct DirReader(PathBuf);
+
+impl DirReader {
+    fn visit_dir(&self, dir: &Path, visit: &mut EntryVisitor) -> Result<(), String> {
+        let mut children = fs::read_dir(dir)
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("can't read '{}': {}", dir.display(), e))?;
+        children.sort_by_key(|child| child.file_name());
+
+        for child in children {
+            if child.file_name() == ".git" { continue }
+            let path = child.path();
+            let meta = path.symlink_metadata().map_err(|e| e.to_string())?;
+            let kind = if meta.is_dir() {
+                EntryKind::Dir
+            } else if meta.file_type().is_symlink() {
+                EntryKind::Symlink(fs::read_link(&path).map_err(|e| e.to_string())?.to_string_lossy().into())
+            } else {
+                EntryKind::File
+            };
+            #[cfg(unix)]
+            let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o7777;
+            #[cfg(not(unix))]
+            let mode = 0o644;
+            let mtime = meta.modified().ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |t| t.as_secs());
+
+            let rel = path.strip_prefix(&self.0).unwrap().to_string_lossy().into_owned();
+            let entry = Entry { path: rel, kind: kind.clone(), mode, mtime };
+            match kind {
+                EntryKind::File => visit(&entry, &mut fs::File::open(&path).map_err(|e| e.to_string())?)?,
+                _ => visit(&entry, &mut std::io::empty())?,
+            }
+            if entry.kind == EntryKind::Dir {
+                self.visit_dir(&path, visit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ArchiveReader for DirReader {
+    fn for_each_entry(&mut self, visit: &mut EntryVisitor) -> Result<(), String> {
+        self.visit_dir(&self.0.clone(), visit)
+    }
+}
+
+/// An entry to write with `build`, keyed by its path.
+#[derive(Debug, Clone)]
+pub struct PackEntry {
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub contents: Vec<u8>,
+}
+
+/// Writes `entries` as an archive of `format`. Entries come out sorted by
+/// path, owned by root, with only the executable bit kept from their mode
+/// and all stamped with `mtime`, so the same files always give the same bytes.
+pub fn build(format: OutputFormat, entries: &BTreeMap<String, PackEntry>, mtime: u64) -> Result<Vec<u8>, String> {
+    let err = |e: io::Error| format!("can't write archive: {}", e);
+    match format {
+        OutputFormat::Tar => build_tar(entries, mtime).map_err(err),
+        OutputFormat::TarGz => {
+            // GzEncoder leaves the header mtime at zero.
+            let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            gz.write_all(&build_tar(entries, mtime).map_err(err)?).map_err(err)?;
+            gz.finish().map_err(err)
+        },
+        OutputFormat::Zip => build_zip(entries, mtime).map_err(|e| format!("can't write archive: {}", e)),
+    }
+}
+
+fn normalized_mode(entry: &PackEntry) -> u32 {
+    match entry.kind == EntryKind::Dir || entry.mode & 0o111 != 0 {
+        true => 0o755,
+        false => 0o644,
+    }
+}
+
+fn build_tar(entries: &BTreeMap<String, PackEntry>, mtime: u64) -> io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (path, entry) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mode(normalized_mode(entry));
+        match &entry.kind {
+            EntryKind::Dir => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, format!("{}/", path), io::empty())?;
+            },
+            EntryKind::Symlink(target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target)?;
+            },
+            EntryKind::File => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(entry.contents.len() as u64);
+                builder.append_data(&mut header, path, &entry.contents[..])?;
+            },
+        }
+    }
+    builder.into_inner()
+}
+
+fn build_zip(entries: &BTreeMap<String, PackEntry>, mtime: u64) -> zip::result::ZipResult<Vec<u8>> {
+    // Zip can't represent anything before 1980.
+    let (year, month, day, hour, minute, second) = civil_utc(mtime.max(315_532_800));
+    let time = zip::DateTime::from_date_and_time(year as u16, month as u8, day as u8, hour as u8, minute as u8, second as u8)
+        .unwrap_or_default();
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .last_modified_time(time);
+
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+    for (path, entry) in entries {
+        let options = options.unix_permissions(normalized_mode(entry));
+        match &entry.kind {
+            EntryKind::Dir => zip.add_directory(path.as_str(), options)?,
+            EntryKind::Symlink(target) => zip.add_symlink(path.as_str(), target.as_str(), options)?,
+            EntryKind::File => {
+                zip.start_file(path.as_str(), options)?;
+                zip.write_all(&entry.contents)?;
+            },
+        }
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Zip timestamps carry no zone, they are read as UTC.
+fn dos_to_unix(dt: zip::DateTime) -> u64 {
+    let (y, m, d) = (dt.year() as i64, dt.month() as i64, dt.day() as i64);
+    // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    (days * 86400 + dt.hour() as i64 * 3600 + dt.minute() as i64 * 60 + dt.second() as i64) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_formats_by_magic_bytes() {
+        assert_eq!(ArchiveFormat::detect_bytes(&[0x1f, 0x8b, 0x08]), Some(ArchiveFormat::TarGz));
+        assert_eq!(ArchiveFormat::detect_bytes(b"BZh91AY"), Some(ArchiveFormat::TarBz2));
+        assert_eq!(ArchiveFormat::detect_bytes(&[0xfd, b'7', b'z', b'X', b'Z', 0]), Some(ArchiveFormat::TarXz));
+        assert_eq!(ArchiveFormat::detect_bytes(&[0x28, 0xb5, 0x2f, 0xfd]), Some(ArchiveFormat::TarZst));
+        assert_eq!(ArchiveFormat::detect_bytes(b"PK\x03\x04"), Some(ArchiveFormat::Zip));
+
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(ArchiveFormat::detect_bytes(&tar), Some(ArchiveFormat::Tar));
+        assert_eq!(ArchiveFormat::detect_bytes(b"<!DOCTYPE html>"), None);
+    }
+
+    #[test]
+    fn converts_zip_timestamps() {
+        let dt = zip::DateTime::from_date_and_time(2020, 9, 13, 12, 26, 40).unwrap();
+        assert_eq!(dos_to_unix(dt), 1_600_000_000);
+    }
+
+    #[test]
+    fn finds_cached_archive_in_any_format() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(find_cached(tmp.path(), "abc"), None);
+        fs::write(tmp.path().join("abc.zip"), "").unwrap();
+        assert_eq!(find_cached(tmp.path(), "abc"), Some(tmp.path().join("abc.zip")));
+    }
+
+    fn zip_file(dir: &Path, entries: &[&str]) -> PathBuf {
+        use std::io::Write;
+        let file = dir.join("x.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&file).unwrap());
+        for path in entries {
+            zip.start_file(*path, zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(b"x").unwrap();
+        }
+        zip.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn reads_directories_like_archives() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join(".git")).unwrap();
+        fs::create_dir_all(tmp.path().join("sub")).unwrap();
+        fs::write(tmp.path().join("sub/b.txt"), "b").unwrap();
+        fs::write(tmp.path().join("a.txt"), "a").unwrap();
+
+        let mut seen = vec![];
+        open(tmp.path()).unwrap().for_each_entry(&mut |entry, contents| {
+            let mut text = String::new();
+            contents.read_to_string(&mut text).unwrap();
+            seen.push((entry.path.clone(), text));
+            Ok(())
+        }).unwrap();
+        assert_eq!(seen, vec![
+            ("a.txt".to_owned(), "a".to_owned()),
+            ("sub".to_owned(), "".to_owned()),
+            ("sub/b.txt".to_owned(), "b".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn finds_common_top_level_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = zip_file(tmp.path(), &["tpl-1.0/a.txt", "tpl-1.0/sub/b.txt"]);
+        assert_eq!(common_prefix(&file).unwrap(), "tpl-1.0/");
+
+        let file = zip_file(tmp.path(), &["a.txt", "sub/b.txt"]);
+        assert_eq!(common_prefix(&file).unwrap(), "");
+
+        let file = zip_file(tmp.path(), &["one/a.txt", "two/b.txt"]);
+        assert_eq!(common_prefix(&file).unwrap(), "");
+    }
+}
@@ -1,21 +1,449 @@
 #![macro_use]
-use std::{env::{Args, self}, path::Path};
+use std::path::Path;
 
-use regit::{app::{Regit, RegitOptions}};
+use clap::{Args, Parser, Subcommand};
+
+use regit::{app::{Regit, RegitOptions}, config::Config, filter::Filter, manifest, options::{ConflictStrategy, Layer, LayerConflict, GitInit, KeepGit, MtimePolicy, Output, OutputFormat}, provenance::Provenance, template::{self, TemplateVars}, util::format_utc};
+
+/// Blazingly fast git repository scaffolding
+#[derive(Debug, Parser)]
+#[command(name = "regit", version, about)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    clone: CloneArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Restore destination contents stashed by a forced clone
+    Unstash {
+        /// Destination the contents were stashed from
+        dest: String,
+    },
+    /// Inspect stashed destination contents
+    #[command(subcommand)]
+    Stash(StashCommand),
+    /// Inspect and edit configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Manage source aliases
+    #[command(subcommand)]
+    Alias(AliasCommand),
+    /// Show where a scaffolded directory came from
+    Info {
+        /// Directory scaffolded by regit
+        #[arg(default_value = ".")]
+        dest: String,
+    },
+    /// Tell whether the template of a scaffolded directory has moved on
+    Status {
+        /// Directories scaffolded by regit
+        #[arg(default_value = ".")]
+        dests: Vec<String>,
+    },
+    /// Show local changes to a scaffolded directory, or upstream template changes
+    Diff {
+        /// Directory scaffolded by regit
+        #[arg(default_value = ".")]
+        dest: String,
+        /// Diff the recorded template version against the latest one instead
+        #[arg(short, long)]
+        upstream: bool,
+    },
+    /// Merge upstream template changes into a scaffolded directory
+    Update {
+        /// Directory scaffolded by regit
+        #[arg(default_value = ".")]
+        dest: String,
+        /// Write conflicting upstream changes to `.rej` files instead of conflict markers
+        #[arg(long)]
+        reject: bool,
+        /// Print more details
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Clone every template listed in `regit.toml`, pinned by `regit.lock`
+    Sync {
+        /// Re-resolve refs and refresh the lock
+        #[arg(short, long)]
+        update: bool,
+        /// Print more details
+        #[arg(short, long)]
+        verbose: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum StashCommand {
+    /// List all stashes
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Print the effective value of a key, e.g. `mode` or `mirrors.github.com/`
+    Get {
+        key: String,
+    },
+    /// Set a key in the global config file
+    Set {
+        key: String,
+        value: String,
+        /// Write to the project's `.regit.toml` instead
+        #[arg(long)]
+        local: bool,
+    },
+    /// List every effective key and where it's set
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+enum AliasCommand {
+    /// List all aliases
+    List,
+    /// Add or replace an alias, e.g. `regit alias add svc gitlab.corp/platform/templates#v3`
+    Add {
+        name: String,
+        source: String,
+        /// Write to the project's `.regit.toml` instead
+        #[arg(long)]
+        local: bool,
+    },
+    /// Remove an alias
+    Remove {
+        name: String,
+        /// Remove from the project's `.regit.toml` instead
+        #[arg(long)]
+        local: bool,
+    },
+}
+
+#[derive(Debug, Args)]
+struct CloneArgs {
+    /// Repository source, e.g. `user/repo/sub/dir#ref`, `https://host/x.tar.gz//sub/dir` or an alias,
+    /// followed by the destination. Several sources are layered in order: `regit base overlay -- dest`
+    #[arg(required = true, value_name = "SRC")]
+    src: Vec<String>,
+    /// Destination directory when layering several sources
+    #[arg(last = true)]
+    dest: Option<String>,
+    /// Only extract files matching this glob, e.g. `src/**`
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Leave out files matching this glob
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// What to do when several layers ship the same file
+    #[arg(long, value_enum, default_value_t = LayerConflict::Precedence)]
+    layer_conflict: LayerConflict,
+    /// Overwrite the destination, stashing its previous contents
+    #[arg(short, long, group = "conflict")]
+    force: bool,
+    /// Only write files that don't exist in the destination yet
+    #[arg(short, long, group = "conflict")]
+    merge: bool,
+    /// Move clashing files aside with a `.bak` suffix
+    #[arg(short, long, group = "conflict")]
+    backup: bool,
+    /// Ask what to do for every clashing file
+    #[arg(short, long, group = "conflict")]
+    interactive: bool,
+    /// Show what would be fetched and written without doing it
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Modification time given to extracted files
+    #[arg(long, value_enum, default_value_t = MtimePolicy::Preserve)]
+    mtime: MtimePolicy,
+    /// Octal mode bits cleared from extracted files, e.g. `027`
+    #[arg(long, default_value = "022", value_parser = parse_umask)]
+    umask: u32,
+    /// Expected SHA-256 of the downloaded archive
+    #[arg(long, value_name = "HEX")]
+    sha256: Option<String>,
+    /// Don't reuse cached archives
+    #[arg(long)]
+    no_cache: bool,
+    /// Don't write `.regit.json` to the destination
+    #[arg(long)]
+    no_provenance: bool,
+    /// Render `{{name}}` and `__name__` placeholders with this variable, e.g. `project_name=demo`
+    #[arg(long = "var", value_name = "KEY=VALUE", value_parser = template::parse_var)]
+    vars: Vec<(String, String)>,
+    /// TOML file of template variables
+    #[arg(long, value_name = "FILE")]
+    vars_file: Option<String>,
+    /// Render the template, asking for variables it declares that weren't given
+    #[arg(long)]
+    template: bool,
+    /// Run shell hooks from the template's `degit.json` without asking
+    #[arg(long)]
+    hooks: bool,
+    /// Apply this unified diff to the extracted files, repeatable
+    #[arg(long = "patch", value_name = "FILE")]
+    patches: Vec<String>,
+    /// Write the selected files to this archive instead of a directory, `-` for stdout
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<String>,
+    /// Archive format of `--output`, guessed from its extension by default
+    #[arg(long, value_enum, requires = "output")]
+    output_format: Option<OutputFormat>,
+    /// Make the destination a git repository with the template as its first commit,
+    /// or only stage the files when it's inside one already
+    #[arg(long, conflicts_with = "output")]
+    git_init: bool,
+    /// Keep the template's git history, only the subdirectory's with `subtree`. Implies git mode
+    #[arg(long, value_enum, value_name = "HISTORY", num_args = 0..=1, require_equals = true,
+        default_missing_value = "full", conflicts_with_all = ["output", "git_init"])]
+    keep_git: Option<KeepGit>,
+    /// Initial branch of `--git-init`
+    #[arg(long, value_name = "NAME", requires = "git_init")]
+    git_branch: Option<String>,
+    /// Subject of the initial commit of `--git-init`
+    #[arg(long, value_name = "MESSAGE", requires = "git_init")]
+    git_message: Option<String>,
+    /// Print more details
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+impl CloneArgs {
+    /// Applies the flags on top of the configured options.
+    fn options(&self, config: &Config) -> Result<RegitOptions, String> {
+        let conflict = if self.force { ConflictStrategy::Overwrite }
+            else if self.merge { ConflictStrategy::Merge }
+            else if self.backup { ConflictStrategy::Backup }
+            else if self.interactive { ConflictStrategy::Interactive }
+            else { ConflictStrategy::Abort };
+
+        let mut vars = match &self.vars_file {
+            Some(file) => template::read_vars_file(Path::new(file))?,
+            None => Default::default(),
+        };
+        vars.extend(self.vars.iter().cloned());
+        let template = (self.template || !vars.is_empty()).then_some(TemplateVars { vars, prompt: true });
+        let output = match &self.output {
+            Some(path) => {
+                let format = self.output_format
+                    .or_else(|| OutputFormat::from_path(path))
+                    .or_else(|| (path == "-").then_some(OutputFormat::Tar))
+                    .ok_or_else(|| format!("can't tell the archive format of '{}', pass --output-format", path))?;
+                Some(Output { format, path: path.clone() })
+            },
+            None => None,
+        };
+        let git_init = self.git_init.then(|| {
+            let defaults = GitInit::default();
+            GitInit {
+                branch: self.git_branch.clone(),
+                message: self.git_message.clone().unwrap_or(defaults.message),
+                ..defaults
+            }
+        });
+
+        let options = config.options();
+        Ok(RegitOptions {
+            filter: Filter::new(&self.include, &self.exclude)?,
+            cache: options.cache && !self.no_cache,
+            conflict,
+            verbose: self.verbose,
+            dry_run: self.dry_run,
+            mtime: self.mtime,
+            umask: self.umask,
+            sha256: self.sha256.clone(),
+            provenance: !self.no_provenance,
+            template,
+            hooks: self.hooks,
+            patches: self.patches.clone(),
+            output,
+            git_init,
+            keep_git: self.keep_git,
+            ..options
+        })
+    }
+}
+
+fn parse_umask(umask: &str) -> Result<u32, String> {
+    u32::from_str_radix(umask, 8)
+        .ok()
+        .filter(|umask| *umask <= 0o777)
+        .ok_or_else(|| format!("'{}' isn't an octal umask", umask))
+}
 
 #[tokio::main]
 async fn main() {
-    let mut args = env::args();
-    args.next();
-    if args.len() < 2 {
-        panic!("INVALID_ARGS: 2 args are required");
+    let cli = Cli::parse();
+    // Keep stdout clean for an archive written there, config warnings included.
+    regit::macros::log_to_stderr(cli.command.is_none() && cli.clone.output.as_deref() == Some("-"));
+    let config = Config::load();
+
+    let result = match cli.command {
+        Some(Command::Unstash { dest }) => config.cache().unstash_files(Path::new(&dest)).map(|_| ()),
+        Some(Command::Stash(StashCommand::List)) => { list_stashes(&config); Ok(()) },
+        Some(Command::Config(command)) => run_config(command),
+        Some(Command::Alias(command)) => run_alias(command, &config),
+        Some(Command::Sync { update, verbose }) => run_sync(update, verbose, &config).await,
+        Some(Command::Info { dest }) => print_info(&dest),
+        Some(Command::Status { dests }) => print_status(&dests, &config),
+        Some(Command::Diff { dest, upstream }) => {
+            Regit::diff(&dest, config.options(), upstream).await.map(|diff| print!("{}", diff))
+        },
+        Some(Command::Update { dest, reject, verbose }) => {
+            Regit::update(&dest, RegitOptions { verbose, ..config.options() }, reject).await
+        },
+        None => run(&cli.clone, &config).await,
+    };
+    if let Err(e) = result {
+        eprintln!("❌ {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: &CloneArgs, config: &Config) -> Result<(), String> {
+    let (sources, dest) = match (&args.output, &args.dest, args.src.split_last()) {
+        // Every positional is a source when writing an archive.
+        (Some(_), None, _) => (&args.src[..], "."),
+        (Some(_), Some(_), _) => return Err(String::from("--output replaces the destination, drop the one after `--`")),
+        (None, Some(dest), _) => (&args.src[..], dest.as_str()),
+        (None, None, Some((dest, sources))) if !sources.is_empty() => (sources, dest.as_str()),
+        _ => return Err(String::from("missing destination, e.g. `regit user/repo my-app`")),
+    };
+    let options = args.options(config)?;
+    if args.keep_git.is_some() && sources.len() > 1 {
+        return Err(String::from("--keep-git can't keep the history of several layers"));
+    }
+    if let [src] = sources {
+        let mut regit = Regit::new(src, options);
+        return regit.clone(dest).await;
+    }
+
+    let layers = sources.iter()
+        .map(|src| Layer { src: src.clone(), sub_dir: None, include: args.include.clone(), exclude: args.exclude.clone() })
+        .collect::<Vec<_>>();
+    let cache = regit::cache::Cache::at(options.cache_dir.as_deref()).load();
+    Regit::compose(&layers, &[], dest, options, args.layer_conflict, cache).await.map(|_| ())
+}
+
+async fn run_sync(update: bool, verbose: bool, config: &Config) -> Result<(), String> {
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    let options = RegitOptions { verbose, ..config.options() };
+    manifest::sync(&cwd, options, update).await.map(|_| ())
+}
+
+fn print_info(dest: &str) -> Result<(), String> {
+    let provenance = Provenance::load(Path::new(dest))?;
+    let or_none = |value: &Option<String>| value.clone().unwrap_or_else(|| String::from("-"));
+    println!("source:   {}", provenance.source);
+    if provenance.resolved_source() != provenance.source {
+        println!("resolved: {}", provenance.resolved_source());
+    }
+    println!("url:      {}", provenance.url);
+    println!("subdir:   {}", if provenance.sub_dir.is_empty() { "-" } else { &provenance.sub_dir });
+    println!("ref:      {}", if provenance._ref.is_empty() { "-" } else { &provenance._ref });
+    println!("commit:   {}", or_none(&provenance.commit));
+    println!("sha256:   {}", or_none(&provenance.archive_sha256));
+    println!("regit:    {}", provenance.version);
+    println!("cloned:   {}", format_utc(provenance.timestamp));
+    for (key, value) in &provenance.vars {
+        println!("var:      {} = {}", key, value);
+    }
+    Ok(())
+}
+
+fn print_status(dests: &[String], config: &Config) -> Result<(), String> {
+    let mut failed = 0;
+    for dest in dests {
+        match Regit::status(dest, config.options()) {
+            Ok(status) => {
+                let recorded = status.provenance.pin().unwrap_or("-");
+                match (&status.latest, status.is_behind()) {
+                    (Some(latest), true) => println!("{}: behind, {} → {} ({})", dest, recorded, latest, status.provenance.source),
+                    (Some(_), false) => println!("{}: up to date at {} ({})", dest, recorded, status.provenance.source),
+                    (None, _) => println!("{}: can't tell without downloading ({})", dest, status.provenance.source),
+                }
+            },
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", dest, e);
+            },
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{} of {} directories couldn't be checked", n, dests.len())),
+    }
+}
+
+fn run_config(command: ConfigCommand) -> Result<(), String> {
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    let layers = Config::layers(Config::path().as_deref(), &cwd, std::env::vars());
+    match command {
+        ConfigCommand::Get { key } => {
+            let value = Config::get(&Config::merge(&layers), &key).ok_or_else(|| format!("'{}' isn't set", key))?;
+            match value {
+                toml::Value::String(s) => println!("{}", s),
+                value => println!("{}", value),
+            }
+        },
+        ConfigCommand::Set { key, value, local } => {
+            let path = Config::target_path(local, &cwd)?;
+            Config::set(&path, &key, &value)?;
+            println!("Set '{}' in '{}'", key, path.display());
+        },
+        ConfigCommand::List => {
+            let mut origins = std::collections::BTreeMap::new();
+            for layer in &layers {
+                for (key, value) in Config::entries(&layer.table) {
+                    origins.insert(key, (value, layer.origin.as_str()));
+                }
+            }
+            if origins.is_empty() {
+                println!("Nothing configured");
+            }
+            for (key, (value, origin)) in origins {
+                println!("{} = {}  # {}", key, value, origin);
+            }
+        },
     }
-    let (src, dest) = (args.next().unwrap(), args.next().unwrap()); 
-    
-    run(&src, &dest).await;
+    Ok(())
 }
 
-async fn run(src: &str, dest: &str) {
-    let mut regit = Regit::new(src, RegitOptions::default());
-    regit.clone(dest).await;
+fn run_alias(command: AliasCommand, config: &Config) -> Result<(), String> {
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    match command {
+        AliasCommand::List => {
+            if config.aliases.is_empty() {
+                println!("No aliases");
+            }
+            for (name, source) in &config.aliases {
+                println!("{} = {}", name, source);
+            }
+        },
+        AliasCommand::Add { name, source, local } => {
+            if name.is_empty() || name.contains(['/', '#', ':', '.']) || name.contains(char::is_whitespace) {
+                return Err(format!("'{}' isn't a valid alias name", name));
+            }
+            let path = Config::target_path(local, &cwd)?;
+            Config::set_value(&path, &format!("aliases.{}", name), toml::Value::String(source))?;
+            println!("Added alias '{}' to '{}'", name, path.display());
+        },
+        AliasCommand::Remove { name, local } => {
+            let path = Config::target_path(local, &cwd)?;
+            Config::unset(&path, &format!("aliases.{}", name))?;
+            println!("Removed alias '{}' from '{}'", name, path.display());
+        },
+    }
+    Ok(())
+}
+
+fn list_stashes(config: &Config) {
+    let stashes = config.cache().stashes();
+    if stashes.is_empty() {
+        println!("No stashes");
+    }
+    for stash in stashes {
+        println!("{}  {}  ({} entries)", stash.id, stash.dest, stash.entries.len());
+    }
 }
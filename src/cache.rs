@@ -1,18 +1,35 @@
-use std::{path::{Path, PathBuf}, collections::HashMap, fs};
+use std::{path::{Path, PathBuf}, collections::HashMap, fs, sync::{Arc, Mutex, MutexGuard}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
 
 use super::*;
-use crate::util::mkdirp;
+use crate::{archive, util::{mkdirp, move_path, remove_tree}};
 
-const CACHE_DIR: &'static str = ".regit";
-const TEMP_DIR: &'static str = ".tmp";
-const CONFIG_FILE: &'static str = "config.json";
+const CACHE_DIR: &str = ".regit";
+const TEMP_DIR: &str = ".tmp";
+const STASH_DIR: &str = ".stash";
+const STASH_FILE: &str = "stash.json";
+const CONFIG_FILE: &str = "config.json";
 
 type CacheTree = HashMap<String, String>;
 
-#[derive(Debug, Default)]
-pub(crate) struct Cache {
+/// Destination contents moved aside before a forced clone.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stash {
+    pub id: String,
+    pub dest: String,
+    pub created: u64,
+    pub entries: Vec<String>,
+}
+
+/// Cloning a `Cache` gives another handle to the same cache, so concurrent
+/// clones see each other's updates and never fetch into the same repository
+/// directory at once.
+#[derive(Debug, Default, Clone)]
+pub struct Cache {
     dir: String,
-    tree: CacheTree
+    tree: Arc<Mutex<CacheTree>>,
+    locks: Arc<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>>,
 }
 impl Cache {
     pub fn new() -> Self {
@@ -27,13 +44,21 @@ impl Cache {
         Cache { dir, ..Default::default() }
     }
 
+    /// The cache at `dir`, or at the default location.
+    pub fn at(dir: Option<&str>) -> Self {
+        match dir {
+            Some(dir) => Self::new_custom(dir),
+            None => Self::new(),
+        }
+    }
+
     pub fn new_custom(dir: &str) -> Self {
         let dir = Path::new(dir);
         mkdirp(dir);
         Cache { dir: dir.to_str().unwrap().to_owned(), ..Default::default() }
     }
 
-    pub fn load(mut self) -> Self {
+    pub fn load(self) -> Self {
         log!("Loading cache from config file...");
 
         let file = fs::File::open(self.cfg_path()).expect("should open file");
@@ -42,57 +67,128 @@ impl Cache {
         for t in tree.values() {
             debug!("Retrieved cache for ref:", t);
         }
-        self.tree = tree;
+        *self.tree_mut() = tree;
         self
     }
 
     pub fn update(&mut self, repo_ref: &str, hash: &str, repo_dir: &str) {
         info!("Updating cache...");
         let repo_sig = format!("{}:{}", repo_dir, repo_ref);
+        let mut tree = self.tree_mut();
 
-        if let Some(cached_hash) = self.tree.get(&repo_sig) {
+        if let Some(cached_hash) = tree.get(&repo_sig) {
             if cached_hash == hash { return; }
-            if !self.tree.values().collect::<Vec<_>>().contains(&&hash.to_string()) {
-                std::fs::remove_dir(format!("{}/{}.tar.gz", repo_dir, hash)).unwrap();
+            let cached_hash = cached_hash.to_owned();
+            tree.insert(repo_sig, hash.into());
+            if !tree.values().any(|h| *h == cached_hash) {
+                if let Some(file) = archive::find_cached(Path::new(repo_dir), &cached_hash) {
+                    fs::remove_file(file).ok();
+                }
             }
+        } else {
+            tree.insert(repo_sig, hash.into());
         }
 
-        self.tree.insert(repo_sig.into(), hash.into());
-
-        let cache_file = fs::File::options().write(true).open(self.cfg_path()).unwrap();
+        let cache_file = fs::File::create(self.cfg_path()).unwrap();
 
-        serde_json::to_writer(cache_file, &self.tree).expect("should serialize config file");
+        serde_json::to_writer(cache_file, &*tree).expect("should serialize config file");
         success!("Updated");
     }
 
-    pub fn clean(&mut self) {
+    pub fn clean(&mut self) -> Result<(), String> {
         warn!("Cleaning all cache files...");
-        cmd!("rm", ["-rf", &self.dir]);
+        let removed = remove_tree(Path::new(&self.dir), Path::new(&self.dir));
+        self.tree_mut().clear();
+        removed?;
         success!("Cache is fresh and shiny ✨");
-        self.tree.clear();
+        Ok(())
+    }
+
+    /// Removes a directory from `temp_dir`, warning about what couldn't be.
+    pub fn remove_temp_dir(&self, dir: &Path) {
+        if let Err(e) = remove_tree(dir, &Path::new(&self.dir).join(TEMP_DIR)) {
+            warn!(e);
+        }
     }
 
 
-    pub fn repair(mut self) -> Self {
+    pub fn repair(self) -> Self {
         info!("Repairing cache directory...");
         success!("Directory fixed!");
         self
     }
 
-    // pub fn stash_files(&self, dir: &Path, dest: &Path) {
+    /// Moves everything inside `dest` into a new stash under the cache directory.
+    pub fn stash_files(&self, dest: &Path) -> Result<Stash, String> {
+        let dest = fs::canonicalize(dest).map_err(|e| format!("can't stash '{}': {}", dest.display(), e))?;
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let id = format!("{}-{:09}", created.as_secs(), created.subsec_nanos());
+        let stash_dir = self.stash_dir().join(&id);
+        let files_dir = stash_dir.join("files");
+        mkdirp(&files_dir);
+
+        info!(format!("Stashing contents of '{}'...", dest.display()));
+        let mut entries = vec![];
+        for entry in fs::read_dir(&dest).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            move_path(&entry.path(), &files_dir.join(entry.file_name()))
+                .map_err(|e| format!("failed to stash '{}': {}", entry.path().display(), e))?;
+            entries.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        entries.sort();
 
-    // }
+        let stash = Stash { id, dest: dest.to_string_lossy().into(), created: created.as_secs(), entries };
+        let file = fs::File::create(stash_dir.join(STASH_FILE)).map_err(|e| e.to_string())?;
+        serde_json::to_writer_pretty(file, &stash).map_err(|e| e.to_string())?;
+        success!(format!("Stashed {} entries as '{}'", stash.entries.len(), stash.id));
+        Ok(stash)
+    }
 
-    // pub fn unstash_files(&self, dir: &Path, dest: &Path) {
+    /// Restores the latest stash taken from `dest`, replacing clashing files.
+    pub fn unstash_files(&self, dest: &Path) -> Result<Stash, String> {
+        let abs_dest = fs::canonicalize(dest).map_err(|e| format!("can't unstash '{}': {}", dest.display(), e))?;
+        let stash = self.stashes().into_iter()
+            .rev()
+            .find(|stash| Path::new(&stash.dest) == abs_dest)
+            .ok_or_else(|| format!("no stash found for '{}'", dest.display()))?;
+        let stash_dir = self.stash_dir().join(&stash.id);
+
+        info!(format!("Restoring stash '{}' to '{}'...", stash.id, abs_dest.display()));
+        for entry in &stash.entries {
+            let target = abs_dest.join(entry);
+            remove_tree(&target, &abs_dest)?;
+            move_path(&stash_dir.join("files").join(entry), &target)
+                .map_err(|e| format!("failed to restore '{}': {}", entry, e))?;
+        }
+        remove_tree(&stash_dir, &self.stash_dir())?;
+        success!(format!("Restored {} entries", stash.entries.len()));
+        Ok(stash)
+    }
 
-    // }
+    /// All stashes, oldest first.
+    pub fn stashes(&self) -> Vec<Stash> {
+        let Ok(dirs) = fs::read_dir(self.stash_dir()) else { return vec![] };
+        let mut stashes: Vec<Stash> = dirs
+            .filter_map(|dir| fs::File::open(dir.ok()?.path().join(STASH_FILE)).ok())
+            .filter_map(|file| serde_json::from_reader(file).ok())
+            .collect();
+        stashes.sort_by(|a, b| a.id.cmp(&b.id));
+        stashes
+    }
 
     pub(crate) fn dir(&self) -> &str { self.dir.as_ref() }
-    pub(crate) fn tree(&self) -> &CacheTree { &self.tree }
-    pub(crate) fn tree_mut(&mut self) -> &mut CacheTree { &mut self.tree }
-    
+    pub(crate) fn tree(&self) -> CacheTree { self.tree_mut().clone() }
+    pub(crate) fn tree_mut(&self) -> MutexGuard<'_, CacheTree> { self.tree.lock().unwrap() }
+
+    /// Waits until no other clone works in `repo_dir` and holds it until the
+    /// returned guard is dropped.
+    pub async fn lock(&self, repo_dir: &Path) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = self.locks.lock().unwrap().entry(repo_dir.to_owned()).or_default().clone();
+        lock.lock_owned().await
+    }
+
     pub fn get_cached_hash(&self, repo_dir: &str, repo_ref: &str) -> Option<String> {
-        if let Some(hash) = self.tree.get(&format!("{}:{}", repo_dir, repo_ref)) {
+        if let Some(hash) = self.tree_mut().get(&format!("{}:{}", repo_dir, repo_ref)) {
             return Some(hash.to_owned())
         }
         None
@@ -100,7 +196,7 @@ impl Cache {
 
     pub fn get_repo_location(&self, hash: &str) -> Option<String> {
         log!(format!("Searching hash location: '{}'...", hash));
-        for (k, v) in self.tree.to_owned() {
+        for (k, v) in self.tree_mut().iter() {
             if v == hash {
                 log!(format!("Hash found at location: '{}'", k));
                 return Some(k.to_owned());
@@ -111,6 +207,20 @@ impl Cache {
 }
 
 impl Cache {
+    /// A fresh, empty scratch directory under the cache. Callers remove it.
+    pub fn temp_dir(&self) -> PathBuf {
+        static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = Path::new(&self.dir).join(TEMP_DIR).join(format!("{}-{}-{}", now.as_secs(), std::process::id(), n));
+        mkdirp(&dir);
+        dir
+    }
+
+    fn stash_dir(&self) -> PathBuf {
+        Path::new(&self.dir).join(STASH_DIR)
+    }
+
     fn cfg_path(&self) -> PathBuf {
         let cfg_path = Path::new(&self.dir).join(CONFIG_FILE);
         if !cfg_path.exists() {
@@ -123,8 +233,6 @@ impl Cache {
 
 #[cfg(test)]
 mod tests {
-    use std::path;
-
     use super::*;
 
     #[test]
@@ -147,7 +255,7 @@ mod tests {
 
     #[test]
     fn loads_cache_tree() {
-        let cache = Cache::new().load();
+        let _cache = Cache::new().load();
 
 
     }
@@ -160,7 +268,37 @@ mod tests {
 
     #[test]
     fn cleans_cache() {
-        let mut cache = Cache::new();
-        cache.clean();
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let mut cache = Cache::new_custom(dir.to_str().unwrap());
+        mkdirp(&dir.join("github.com/user/repo"));
+        fs::write(dir.join("github.com/user/repo/abc.tar.gz"), "").unwrap();
+        cache.clean().unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn stashes_and_restores_destination() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = Cache::new_custom(tmp.path().join("cache").to_str().unwrap());
+        let dest = tmp.path().join("dest");
+        mkdirp(&dest.join("src"));
+        fs::write(dest.join("a.txt"), "mine").unwrap();
+        fs::write(dest.join("src/lib.rs"), "// mine").unwrap();
+
+        let stash = cache.stash_files(&dest).unwrap();
+        assert_eq!(stash.entries, vec!["a.txt", "src"]);
+        assert_eq!(dest.read_dir().unwrap().count(), 0, "dest should be emptied");
+        assert_eq!(cache.stashes(), vec![stash.clone()]);
+
+        fs::write(dest.join("a.txt"), "template").unwrap();
+        fs::write(dest.join("b.txt"), "template").unwrap();
+        let restored = cache.unstash_files(&dest).unwrap();
+        assert_eq!(restored.id, stash.id);
+        assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "mine");
+        assert_eq!(fs::read_to_string(dest.join("src/lib.rs")).unwrap(), "// mine");
+        assert!(dest.join("b.txt").exists(), "should keep files the clone added");
+        assert!(cache.stashes().is_empty());
+        assert!(cache.unstash_files(&dest).is_err());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,487 @@
+use std::{collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Deserializer};
+use toml::{Table, Value};
+
+use super::*;
+use crate::{app::RegitOptions, cache::Cache, options::{GitServer, ValidModes}, repository::has_archive_ext};
+
+const CONFIG_DIR: &str = "regit";
+const CONFIG_FILE: &str = "config.toml";
+const PROJECT_FILE: &str = ".regit.toml";
+
+/// Environment variables and the config keys they set.
+const ENV_VARS: [(&str, &str); 5] = [
+    ("REGIT_HOST", "host"),
+    ("REGIT_MODE", "mode"),
+    ("REGIT_CACHE", "cache"),
+    ("REGIT_CACHE_DIR", "cache_dir"),
+    ("REGIT_PROXY", "proxy"),
+];
+
+/// Configuration merged from `~/.config/regit/config.toml`, the nearest
+/// project `.regit.toml` and `REGIT_*` environment variables, later layers
+/// taking precedence. Command line flags override all of them.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Host used for sources without one, `github.com` by default.
+    pub host: Option<String>,
+    /// Mode used for hosted repositories, `tar` or `git`.
+    pub mode: Option<ValidModes>,
+    /// Whether cached archives are reused.
+    pub cache: Option<bool>,
+    /// Cache location, `~/.regit` by default.
+    pub cache_dir: Option<String>,
+    /// Proxy for archive downloads and git's HTTP remotes.
+    pub proxy: Option<String>,
+    /// Access tokens by host, sent with archive downloads from that host and
+    /// given to git for its HTTPS remotes there.
+    #[serde(default)]
+    pub auth: BTreeMap<String, String>,
+    /// URL prefixes rewritten before anything is fetched, like git's
+    /// `insteadOf`. Each prefix maps to one or more replacements which are
+    /// tried in order:
+    ///
+    /// ```toml
+    /// [mirrors]
+    /// "github.com/" = ["git.mirror.corp/github/", "github.com/"]
+    /// ```
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub mirrors: BTreeMap<String, Vec<String>>,
+    /// Short names for frequently used sources.
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Kind of server on self-hosted domains, e.g. `"git.corp" = "gitlab"`.
+    /// github.com, gitlab.com, bitbucket.org and git.sr.ht are known already.
+    #[serde(default)]
+    pub servers: BTreeMap<String, GitServer>,
+}
+
+/// One source of configuration and the keys it sets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub origin: String,
+    pub table: Table,
+}
+
+impl Config {
+    /// Path of the global config file.
+    pub fn path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join(CONFIG_DIR).join(CONFIG_FILE))
+    }
+
+    /// Path of the nearest `.regit.toml` in `dir` or its ancestors.
+    pub fn project_path(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors().map(|d| d.join(PROJECT_FILE)).find(|p| p.is_file())
+    }
+
+    /// Path `regit config set` writes to: the global file, or with `local`
+    /// the nearest project file, created in `dir` when there is none.
+    pub fn target_path(local: bool, dir: &Path) -> Result<PathBuf, String> {
+        match local {
+            true => Ok(Self::project_path(dir).unwrap_or_else(|| dir.join(PROJECT_FILE))),
+            false => Self::path().ok_or_else(|| String::from("can't find the user's config directory")),
+        }
+    }
+
+    /// Loads the configuration for the current directory and environment.
+    pub fn load() -> Self {
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let layers = Self::layers(Self::path().as_deref(), &cwd, std::env::vars());
+        Self::from_table(Self::merge(&layers)).unwrap_or_default()
+    }
+
+    /// Reads every layer that exists, in precedence order. Broken layers are
+    /// reported and left out so they don't take the others down with them.
+    pub fn layers(global: Option<&Path>, cwd: &Path, env: impl IntoIterator<Item = (String, String)>) -> Vec<Layer> {
+        let files = [global.map(Path::to_owned), Self::project_path(cwd)];
+        let mut layers: Vec<Layer> = files.into_iter().flatten()
+            .filter(|path| path.is_file())
+            .filter_map(|path| match Self::read_table(&path) {
+                Ok(table) => Some(Layer { origin: path.display().to_string(), table }),
+                Err(e) => {
+                    warn!(format!("Ignoring '{}': {}", path.display(), e));
+                    None
+                }
+            })
+            .collect();
+
+        let env: HashMap<String, String> = env.into_iter().collect();
+        let table: Table = ENV_VARS.iter()
+            .filter_map(|(var, key)| Some((key.to_string(), parse_value(env.get(*var)?))))
+            .collect();
+        match Self::from_table(table.clone()) {
+            Ok(_) if table.is_empty() => {},
+            Ok(_) => layers.push(Layer { origin: String::from("environment"), table }),
+            Err(e) => { warn!(format!("Ignoring REGIT_* environment variables: {}", e)); },
+        }
+        layers
+    }
+
+    /// Merges layers key by key, later ones winning. Tables like `mirrors`
+    /// are merged entry by entry rather than replaced.
+    pub fn merge(layers: &[Layer]) -> Table {
+        let mut merged = Table::new();
+        for layer in layers {
+            for (key, value) in &layer.table {
+                match (merged.get_mut(key), value) {
+                    (Some(Value::Table(into)), Value::Table(from)) => into.extend(from.clone()),
+                    _ => { merged.insert(key.to_owned(), value.to_owned()); },
+                }
+            }
+        }
+        merged
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        Self::from_table(toml::from_str(contents).map_err(|e| e.to_string())?)
+    }
+
+    pub fn from_table(table: Table) -> Result<Self, String> {
+        let config: Self = Value::Table(table).try_into().map_err(|e: toml::de::Error| e.message().to_owned())?;
+        match config.mode {
+            None | Some(ValidModes::Tar) | Some(ValidModes::Git) => Ok(config),
+            Some(_) => Err(String::from("mode must be 'tar' or 'git'")),
+        }
+    }
+
+    fn read_table(path: &Path) -> Result<Table, String> {
+        let table: Table = toml::from_str(&fs::read_to_string(path).map_err(|e| e.to_string())?)
+            .map_err(|e| e.to_string())?;
+        Self::from_table(table.clone())?;
+        Ok(table)
+    }
+
+    /// Sets the dotted `key` to `value` in the file at `path`, checking that
+    /// the result is still a valid config before writing it.
+    pub fn set(path: &Path, key: &str, value: &str) -> Result<(), String> {
+        Self::set_value(path, key, parse_value(value))
+    }
+
+    pub fn set_value(path: &Path, key: &str, value: Value) -> Result<(), String> {
+        let mut table = Self::read_file(path)?;
+        match key.split_once('.') {
+            Some((section, entry)) => {
+                let section = table.entry(section).or_insert_with(|| Value::Table(Table::new()));
+                let Value::Table(section) = section else { return Err(format!("'{}' isn't a table", key)) };
+                section.insert(entry.to_owned(), value);
+            },
+            None => { table.insert(key.to_owned(), value); },
+        }
+        Self::from_table(table.clone())?;
+        Self::write_file(path, &table)
+    }
+
+    /// Removes the dotted `key` from the file at `path`.
+    pub fn unset(path: &Path, key: &str) -> Result<(), String> {
+        let mut table = Self::read_file(path)?;
+        let removed = match key.split_once('.') {
+            Some((section, entry)) if !table.contains_key(key) => table.get_mut(section)
+                .and_then(Value::as_table_mut)
+                .and_then(|section| section.remove(entry)),
+            _ => table.remove(key),
+        };
+        if removed.is_none() {
+            return Err(format!("'{}' isn't set in '{}'", key, path.display()));
+        }
+        Self::write_file(path, &table)
+    }
+
+    fn read_file(path: &Path) -> Result<Table, String> {
+        match path.is_file() {
+            true => toml::from_str(&fs::read_to_string(path).map_err(|e| e.to_string())?).map_err(|e| e.to_string()),
+            false => Ok(Table::new()),
+        }
+    }
+
+    fn write_file(path: &Path, table: &Table) -> Result<(), String> {
+        if let Some(dir) = path.parent() { crate::util::mkdirp(dir) }
+        fs::write(path, toml::to_string_pretty(table).map_err(|e| e.to_string())?).map_err(|e| e.to_string())
+    }
+
+    /// Expands an alias at the start of `src`. The alias may be followed by
+    /// a subdirectory and a ref, which are appended to its source or replace
+    /// its ref: with `svc = "gitlab.corp/platform/templates#v3"`, `svc/grpc#v4`
+    /// becomes `gitlab.corp/platform/templates/grpc#v4`. Other sources are
+    /// returned unchanged.
+    pub fn expand_alias(&self, src: &str) -> String {
+        let end = src.find(['/', '#']).unwrap_or(src.len());
+        let Some(target) = self.aliases.get(&src[..end]) else { return src.to_owned() };
+
+        let (base, target_ref) = match target.split_once('#') {
+            Some((base, _ref)) => (base, Some(_ref)),
+            None => (target.as_str(), None),
+        };
+        let (sub_dir, _ref) = match src[end..].split_once('#') {
+            Some((sub_dir, _ref)) => (sub_dir, Some(_ref)),
+            None => (&src[end..], target_ref),
+        };
+        let sub_dir = sub_dir.trim_matches('/');
+
+        let mut expanded = base.trim_end_matches('/').to_owned();
+        if !sub_dir.is_empty() {
+            // Archive sources select their subdirectory with `//`.
+            let scheme_end = base.find("://").map_or(0, |i| i + 3);
+            let separator = match has_archive_ext(base) && !base[scheme_end..].contains("//") {
+                true => "//",
+                false => "/",
+            };
+            expanded = format!("{}{}{}", expanded, separator, sub_dir);
+        }
+        if let Some(_ref) = _ref {
+            expanded = format!("{}#{}", expanded, _ref);
+        }
+        expanded
+    }
+
+    /// Looks up a dotted key like `mode` or `mirrors.github.com/`.
+    pub fn get(table: &Table, key: &str) -> Option<Value> {
+        if let Some(value) = table.get(key) { return Some(value.to_owned()) }
+        let (section, entry) = key.split_once('.')?;
+        table.get(section)?.as_table()?.get(entry).cloned()
+    }
+
+    /// Flattens a table into `(dotted key, value)` pairs.
+    pub fn entries(table: &Table) -> Vec<(String, Value)> {
+        table.iter()
+            .flat_map(|(key, value)| match value {
+                Value::Table(section) => section.iter()
+                    .map(|(entry, value)| (format!("{}.{}", key, entry), value.to_owned()))
+                    .collect(),
+                value => vec![(key.to_owned(), value.to_owned())],
+            })
+            .collect()
+    }
+
+    /// Base options for a clone, before command line flags are applied.
+    pub fn options(&self) -> RegitOptions {
+        RegitOptions {
+            cache: self.cache.unwrap_or(true),
+            mode: self.mode,
+            cache_dir: self.cache_dir.as_deref().map(expand_home),
+            proxy: self.proxy.clone().unwrap_or_default(),
+            auth: self.auth.clone(),
+            config: self.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// The cache at the configured location.
+    pub fn cache(&self) -> Cache {
+        Cache::at(self.cache_dir.as_deref().map(expand_home).as_deref())
+    }
+
+    /// Normalized default host.
+    pub fn host(&self) -> String {
+        self.host.as_deref().map_or(String::from("github.com"), normalize_host)
+    }
+
+    /// Kind of server running on `host`, if known.
+    pub fn server(&self, host: &str) -> Option<GitServer> {
+        if let Some(server) = self.servers.get(host) { return Some(*server) }
+        match host {
+            "github.com" => Some(GitServer::GitHub),
+            "gitlab.com" => Some(GitServer::GitLab),
+            "bitbucket.org" => Some(GitServer::BitBucket),
+            "git.sr.ht" => Some(GitServer::SourceHut),
+            _ => None,
+        }
+    }
+
+    /// URLs to fetch `url` from, in order. The longest matching prefix wins;
+    /// prefixes may leave out the scheme, in which case the one from `url` is
+    /// kept. Without a matching rule that's just `url` itself.
+    pub fn mirrors_for(&self, url: &str) -> Vec<String> {
+        let (scheme, rest) = match url.split_once("://") {
+            Some((scheme, rest)) => (format!("{}://", scheme), rest),
+            None => (String::new(), url),
+        };
+        let rule = self.mirrors.iter()
+            .filter_map(|(prefix, targets)| {
+                let tail = url.strip_prefix(prefix.as_str()).or_else(|| rest.strip_prefix(prefix.as_str()))?;
+                Some((prefix.len(), tail, targets))
+            })
+            .max_by_key(|(len, ..)| *len);
+
+        match rule {
+            Some((_, tail, targets)) => targets.iter()
+                .map(|target| match target.contains("://") {
+                    true => format!("{}{}", target, tail),
+                    false => format!("{}{}{}", scheme, target, tail),
+                })
+                .collect(),
+            None => vec![url.to_owned()],
+        }
+    }
+}
+
+/// Expands the short host names accepted in sources, e.g. `gitlab:user/repo`.
+pub fn normalize_host(host: &str) -> String {
+    match host {
+        "github" => "github.com",
+        "gitlab" => "gitlab.com",
+        "bitbucket" => "bitbucket.org",
+        "sourcehut" | "srht" => "git.sr.ht",
+        host => host,
+    }.to_owned()
+}
+
+/// Reads a value the way TOML would, falling back to a plain string so
+/// `regit config set host gitlab.com` doesn't need quotes.
+fn parse_value(value: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut t| t.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_owned()))
+}
+
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).display().to_string(),
+        _ => path.to_owned(),
+    }
+}
+
+/// Accepts a single replacement as well as a list of them.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany { One(String), Many(Vec<String>) }
+
+    let map = BTreeMap::<String, OneOrMany>::deserialize(deserializer)?;
+    Ok(map.into_iter()
+        .map(|(prefix, targets)| match targets {
+            OneOrMany::One(target) => (prefix, vec![target]),
+            OneOrMany::Many(targets) => (prefix, targets),
+        })
+        .collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_urls_through_mirrors() {
+        let config = Config::parse(r#"
+            [mirrors]
+            "github.com/" = "git.mirror.corp/github/"
+            "github.com/acme/" = ["https://gitea.acme.internal/", "github.com/acme/"]
+        "#).unwrap();
+
+        assert_eq!(
+            config.mirrors_for("https://github.com/solidjs/templates"),
+            vec!["https://git.mirror.corp/github/solidjs/templates"],
+        );
+        assert_eq!(
+            config.mirrors_for("https://github.com/acme/tpl"),
+            vec!["https://gitea.acme.internal/tpl", "https://github.com/acme/tpl"],
+            "the longest prefix should win and keep its order",
+        );
+        assert_eq!(config.mirrors_for("https://gitlab.com/a/b"), vec!["https://gitlab.com/a/b"]);
+    }
+
+    #[test]
+    fn rejects_malformed_config() {
+        assert!(Config::parse("[mirrors]\n\"github.com/\" = 1").is_err());
+        assert!(Config::parse("mode = \"local\"").is_err());
+        assert!(Config::parse("colour = true").is_err());
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn layers_global_project_and_environment() {
+        let tmp = tempfile::tempdir().unwrap();
+        let global = tmp.path().join("config.toml");
+        fs::write(&global, "host = \"gitlab\"\nmode = \"git\"\ncache = false\n[mirrors]\n\"a/\" = \"b/\"\n").unwrap();
+        let project = tmp.path().join("project");
+        crate::util::mkdirp(&project.join("nested"));
+        fs::write(project.join(PROJECT_FILE), "mode = \"tar\"\n[mirrors]\n\"c/\" = \"d/\"\n").unwrap();
+
+        let env = vec![("REGIT_CACHE".to_owned(), "true".to_owned()), ("HOME".to_owned(), "/".to_owned())];
+        let layers = Config::layers(Some(&global), &project.join("nested"), env);
+        assert_eq!(layers.len(), 3);
+        let config = Config::from_table(Config::merge(&layers)).unwrap();
+
+        assert_eq!(config.host(), "gitlab.com");
+        assert_eq!(config.mode, Some(ValidModes::Tar), "the project file should override the global one");
+        assert_eq!(config.cache, Some(true), "the environment should override both files");
+        assert_eq!(config.mirrors.len(), 2, "tables should be merged entry by entry");
+        assert!(config.options().cache);
+    }
+
+    #[test]
+    fn skips_broken_layers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let global = tmp.path().join("config.toml");
+        fs::write(&global, "mode = 1").unwrap();
+        let env = vec![("REGIT_HOST".to_owned(), "gitlab.com".to_owned())];
+
+        let layers = Config::layers(Some(&global), tmp.path(), env);
+        assert_eq!(layers.iter().map(|l| l.origin.as_str()).collect::<Vec<_>>(), ["environment"]);
+    }
+
+    #[test]
+    fn sets_and_gets_dotted_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("dir/config.toml");
+        Config::set(&file, "cache", "false").unwrap();
+        Config::set(&file, "host", "gitlab.com").unwrap();
+        Config::set(&file, "mirrors.github.com/", "git.mirror.corp/github/").unwrap();
+        assert!(Config::set(&file, "mode", "archive").is_err(), "invalid values shouldn't be written");
+
+        let table: Table = toml::from_str(&fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(Config::get(&table, "cache"), Some(Value::Boolean(false)));
+        assert_eq!(Config::get(&table, "host"), Some(Value::String("gitlab.com".into())));
+        assert_eq!(Config::get(&table, "mirrors.github.com/"), Some(Value::String("git.mirror.corp/github/".into())));
+        assert_eq!(Config::entries(&table).len(), 3);
+    }
+
+    #[test]
+    fn tells_servers_of_known_and_configured_hosts() {
+        let config = Config::parse(r#"
+            [servers]
+            "git.corp" = "gitlab"
+        "#).unwrap();
+
+        assert_eq!(config.server("git.corp"), Some(GitServer::GitLab));
+        assert_eq!(config.server("gitlab.com"), Some(GitServer::GitLab));
+        assert_eq!(config.server("bitbucket.org"), Some(GitServer::BitBucket));
+        assert_eq!(config.server("git.example.com"), None);
+        assert!(Config::parse("servers = { \"git.corp\" = \"gitea\" }").is_err());
+    }
+
+    #[test]
+    fn expands_aliases_with_subdir_and_ref() {
+        let config = Config::parse(r#"
+            [aliases]
+            svc = "gitlab.corp/platform/templates/rust-service#v3"
+            tpl = "https://example.com/tpl-1.0.tar.gz"
+            local = "./templates/"
+        "#).unwrap();
+
+        assert_eq!(config.expand_alias("svc"), "gitlab.corp/platform/templates/rust-service#v3");
+        assert_eq!(config.expand_alias("svc/grpc#v4"), "gitlab.corp/platform/templates/rust-service/grpc#v4");
+        assert_eq!(config.expand_alias("svc#v4"), "gitlab.corp/platform/templates/rust-service#v4");
+        assert_eq!(config.expand_alias("svc/grpc"), "gitlab.corp/platform/templates/rust-service/grpc#v3");
+        assert_eq!(config.expand_alias("tpl/api"), "https://example.com/tpl-1.0.tar.gz//api");
+        assert_eq!(config.expand_alias("local/api"), "./templates/api");
+        assert_eq!(config.expand_alias("svcs/repo"), "svcs/repo", "only whole names should match");
+        assert_eq!(config.expand_alias("user/repo#main"), "user/repo#main");
+    }
+
+    #[test]
+    fn unsets_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("config.toml");
+        Config::set_value(&file, "aliases.svc", Value::String("user/repo".into())).unwrap();
+        Config::set(&file, "cache", "false").unwrap();
+
+        Config::unset(&file, "aliases.svc").unwrap();
+        assert!(Config::unset(&file, "aliases.svc").is_err());
+        let table = Config::read_file(&file).unwrap();
+        assert_eq!(Config::entries(&table), vec![("cache".to_owned(), Value::Boolean(false))]);
+    }
+}
@@ -0,0 +1,79 @@
+use regex::Regex;
+
+/// Include and exclude globs selecting which template files get extracted.
+/// `*` and `?` stay within a path segment, `**` crosses them. Patterns
+/// without a `/` match at any depth, and a pattern matching a directory
+/// matches everything below it, like in `.gitignore`.
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        let compile = |globs: &[String]| globs.iter().map(|glob| glob_to_regex(glob)).collect::<Result<Vec<_>, _>>();
+        Ok(Self { include: compile(include)?, exclude: compile(exclude)? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the file at relative `path` should be extracted.
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.trim_end_matches('/');
+        (self.include.is_empty() || self.include.iter().any(|re| re.is_match(path)))
+            && !self.exclude.iter().any(|re| re.is_match(path))
+    }
+}
+
+fn glob_to_regex(glob: &str) -> Result<Regex, String> {
+    let anchored = glob.trim_end_matches('/').contains('/');
+    let mut chars = glob.trim_start_matches('/').trim_end_matches('/').chars().peekable();
+    let mut re = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                match chars.peek() {
+                    Some('/') => { chars.next(); re.push_str("(?:.*/)?") },
+                    _ => re.push_str(".*"),
+                }
+            },
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push_str("(?:/.*)?$");
+    Regex::new(&re).map_err(|e| format!("invalid glob '{}': {}", glob, e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+        let owned = |globs: &[&str]| globs.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+        Filter::new(&owned(include), &owned(exclude)).unwrap()
+    }
+
+    #[test]
+    fn matches_globs() {
+        let f = filter(&["src/**/*.rs", "Cargo.toml"], &["*_test.rs", "src/gen"]);
+        assert!(f.matches("src/main.rs"));
+        assert!(f.matches("src/a/b/lib.rs"));
+        assert!(f.matches("Cargo.toml"));
+        assert!(f.matches("crates/x/Cargo.toml"), "slashless patterns match at any depth");
+        assert!(!f.matches("README.md"));
+        assert!(!f.matches("src/a/lib_test.rs"));
+        assert!(!f.matches("src/gen/out.rs"), "excluded directories exclude their contents");
+        assert!(!f.matches("tests/src/main.rs"), "patterns with a slash are anchored");
+
+        assert!(filter(&[], &[]).matches("anything"));
+        assert!(filter(&["docs"], &[]).matches("docs/guide/intro.md"));
+        assert!(filter(&["?.txt"], &[]).matches("a.txt") && !filter(&["?.txt"], &[]).matches("ab.txt"));
+    }
+}
@@ -9,12 +9,23 @@
 pub mod macros;
 
 pub mod util;
+pub mod archive;
 pub mod cache;
+pub mod config;
 pub mod options;
+pub mod filter;
 pub mod repository;
+pub mod manifest;
+pub mod provenance;
+pub mod merge;
+pub mod patch;
+pub mod template;
+pub mod actions;
 pub mod traits;
 pub mod prelude;
 pub mod app;
+#[cfg(test)]
+mod test_util;
 
 pub use prelude::*;
 
@@ -1,6 +1,23 @@
-use colored::Colorize;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 static mut VERBOSE: bool = true;
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Sends log output to stderr, keeping stdout free for data such as an
+/// archive written with `-o -`.
+pub fn log_to_stderr(enabled: bool) {
+    TO_STDERR.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn logs_to_stderr() -> bool {
+    TO_STDERR.load(Ordering::Relaxed)
+}
+
+macro_rules! emit {
+    ( $( $arg:tt )* ) => {
+        if crate::macros::logs_to_stderr() { eprintln!($($arg)*) } else { println!($($arg)*) }
+    };
+}
 
 macro_rules! cmd {
     ( $program:expr ) => {
@@ -47,43 +64,43 @@ macro_rules! logger {
 
 macro_rules! log {
     ( $( $arg:expr $(,)*)* ) => {
-        println!("{}", logger!("   ", $({$arg})*).dimmed());
+        emit!("{}", logger!("   ", $({$arg})*).dimmed());
     };
 }
 
 macro_rules! info {
     ( $( $arg:expr $(,)*)* ) => {
-        println!("{}", logger!("ℹ️  ", $({$arg})*).blue());
+        emit!("{}", logger!("ℹ️  ", $({$arg})*).blue());
     };
 }
 
 macro_rules! debug {
     ( $( $arg:expr $(,)*)* ) => {
-        println!("{}", logger!("   ", $({$arg})*).magenta());
+        emit!("{}", logger!("   ", $({$arg})*).magenta());
     };
 }
 
 macro_rules! success {
     ( $( $arg:expr $(,)*)* ) => {
-        println!("{}{}", logger!("✅ ", $({$arg})*).green(), "\n");
+        emit!("{}{}", logger!("✅ ", $({$arg})*).green(), "\n");
     };
 }
 
 macro_rules! warn {
     ( $( $arg:expr $(,)*)* ) => {
-        println!("{}{}", logger!("⚠️  ", $({$arg})*).yellow(), "\n");
+        emit!("{}{}", logger!("⚠️  ", $({$arg})*).yellow(), "\n");
     };
 }
 
 macro_rules! error {
     ( $( $arg:expr $(,)*)* ) => {
-        println!("{}{}", logger!("❌ ", $({$arg})*).red(), "\n");
+        emit!("{}{}", logger!("❌ ", $({$arg})*).red(), "\n");
     };
 }
 
 macro_rules! done {
     ( $( $arg:expr $(,)*)* ) => {
-        println!("{}{}", logger!("✨ ", $({$arg})*).green(), "✨\n");
+        emit!("{}{}", logger!("✨ ", $({$arg})*).green(), "✨\n");
     };
 }
 
@@ -91,7 +108,7 @@ macro_rules! done {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use crate::Colorize;
 
     #[test]
     fn log_test() {
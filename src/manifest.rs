@@ -0,0 +1,323 @@
+use std::{collections::BTreeSet, fs, path::{Component, Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+use super::*;
+use crate::{
+    app::{Regit, RegitOptions},
+    cache::Cache,
+    options::{ConflictStrategy, Layer, LayerConflict, ValidModes},
+    traits::AsStr,
+};
+
+pub const MANIFEST_FILE: &str = "regit.toml";
+pub const LOCK_FILE: &str = "regit.lock";
+
+/// Templates vendored into a project, listed in `regit.toml`:
+///
+/// ```toml
+/// [[clone]]
+/// src = "org/ci-templates/github#v2"
+/// dest = ".github"
+///
+/// [[clone]]
+/// src = "lint"          # aliases work here too
+/// dest = "."
+/// conflict = "merge"
+/// patches = ["patches/lint.diff"]
+///
+/// [[compose]]
+/// dest = "service"
+/// layer_conflict = "error"
+/// layers = [
+///     { src = "org/templates/base" },
+///     { src = "org/templates", subdir = "auth", exclude = ["*.md"] },
+/// ]
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub clone: Vec<ManifestEntry>,
+    #[serde(default)]
+    pub compose: Vec<ComposeEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub src: String,
+    /// Destination relative to the manifest.
+    pub dest: String,
+    /// What to do with files that already exist, `overwrite` by default.
+    /// Unlike `--force`, the destination isn't stashed first.
+    pub conflict: Option<ConflictStrategy>,
+    pub mode: Option<ValidModes>,
+    /// Expected SHA-256 of an archive source.
+    pub sha256: Option<String>,
+    /// Unified diffs applied after extraction, relative to the manifest.
+    #[serde(default)]
+    pub patches: Vec<String>,
+}
+
+/// Several sources layered into one destination, later layers winning.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComposeEntry {
+    /// Destination relative to the manifest.
+    pub dest: String,
+    pub layers: Vec<Layer>,
+    /// What to do with files that already exist, `overwrite` by default.
+    pub conflict: Option<ConflictStrategy>,
+    /// What to do when layers ship the same file, `precedence` by default.
+    #[serde(default)]
+    pub layer_conflict: LayerConflict,
+    /// Unified diffs applied to the composed files, relative to the manifest.
+    #[serde(default)]
+    pub patches: Vec<String>,
+}
+
+/// Hashes resolved by the last `regit sync`, kept in `regit.lock` so the next
+/// one clones exactly the same files.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lock {
+    #[serde(default)]
+    pub clone: Vec<LockEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockEntry {
+    pub src: String,
+    pub dest: String,
+    /// Commit, or archive SHA-256. Local directory copies have none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(MANIFEST_FILE);
+        let contents = fs::read_to_string(&path).map_err(|e| format!("can't read '{}': {}", path.display(), e))?;
+        Self::parse(&contents).map_err(|e| format!("invalid '{}': {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let manifest: Self = toml::from_str(contents).map_err(|e| e.to_string())?;
+        for entry in &manifest.clone {
+            if entry.conflict == Some(ConflictStrategy::Interactive) {
+                return Err(format!("'{}' can't be interactive, entries are cloned concurrently", entry.src));
+            }
+            if let Some(mode @ (ValidModes::Archive | ValidModes::Local)) = entry.mode {
+                return Err(format!("'{}' can't force {:?} mode, use 'tar' or 'git'", entry.src, mode));
+            }
+        }
+        for entry in &manifest.compose {
+            if entry.layers.is_empty() {
+                return Err(format!("'{}' has no layers to compose", entry.dest));
+            }
+            if entry.conflict == Some(ConflictStrategy::Interactive) {
+                return Err(format!("'{}' can't be interactive, entries are synced unattended", entry.dest));
+            }
+        }
+        // Each destination records one `.regit.json`, and clones run at once.
+        let mut dests = BTreeSet::new();
+        let all = manifest.clone.iter().map(|e| &e.dest).chain(manifest.compose.iter().map(|e| &e.dest));
+        for dest in all {
+            let path = Path::new(dest);
+            if path.has_root() || path.components().any(|c| c == Component::ParentDir) {
+                return Err(format!("'{}' must stay within the project, without '..'", dest));
+            }
+            let normalized: PathBuf = path.components().filter(|c| *c != Component::CurDir).collect();
+            if !dests.insert(normalized) {
+                return Err(format!("'{}' is the destination of more than one entry", dest));
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+impl Lock {
+    fn entry(&self, src: &str, dest: &str) -> Option<&LockEntry> {
+        self.clone.iter().find(|entry| entry.src == src && entry.dest == dest)
+    }
+
+    /// Loads `regit.lock`, or an empty lock when there's none yet.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(LOCK_FILE);
+        if !path.is_file() { return Ok(Self::default()) }
+        let contents = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+        toml::from_str(&contents).map_err(|e| format!("invalid '{}': {}", path.display(), e))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), String> {
+        let contents = toml::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(dir.join(LOCK_FILE), contents).map_err(|e| e.to_string())
+    }
+
+    pub fn hash(&self, src: &str, dest: &str) -> Option<&str> {
+        self.entry(src, dest).and_then(|entry| entry.hash.as_deref())
+    }
+}
+
+/// Clones every entry of the manifest in `dir` concurrently through one
+/// shared cache, then composes the layered entries in order. Locked entries
+/// and layers are pinned to their recorded hash unless `update` is set; the
+/// lock is then rewritten with what was cloned. Entries that fail keep their
+/// previous lock entry and are reported together. Nothing is asked on the
+/// terminal, so template hooks only run when `options.hooks` is set.
+pub async fn sync(dir: &Path, options: RegitOptions, update: bool) -> Result<Lock, String> {
+    let manifest = Manifest::load(dir)?;
+    let lock = Lock::load(dir)?;
+    let cache = Cache::at(options.cache_dir.as_deref()).load();
+    let total = manifest.clone.len() + manifest.compose.len();
+    info!(format!("Syncing {} templates...", total));
+
+    let tasks = manifest.clone.iter().map(|entry| {
+        let src = entry.src.clone();
+        let pinned = lock.hash(&entry.src, &entry.dest).filter(|_| !update).map(str::to_owned);
+        let dest = dir.join(&entry.dest);
+        let options = RegitOptions {
+            conflict: entry.conflict.unwrap_or(ConflictStrategy::Overwrite),
+            mode: entry.mode.or(options.mode),
+            sha256: entry.sha256.clone(),
+            patches: entry.patches.iter().map(|p| dir.join(p).as_str().to_owned()).collect(),
+            stash: false,
+            interactive: false,
+            ..options.clone()
+        };
+        let cache = cache.clone();
+
+        tokio::spawn(async move {
+            let mut regit = Regit::with_cache(&src, options, cache);
+            if let Some(hash) = &pinned { regit.pin(hash) }
+            regit.clone(dest.as_str()).await?;
+            Ok::<_, String>(regit.hash().map(str::to_owned))
+        })
+    });
+    let results = futures::future::join_all(tasks).await;
+
+    let mut synced = Lock::default();
+    let mut errors = vec![];
+    for (entry, result) in manifest.clone.iter().zip(results) {
+        let result = result.map_err(|e| e.to_string()).and_then(|r| r);
+        let hash = match result {
+            Ok(hash) => hash,
+            Err(e) => {
+                errors.push(format!("{} → {}: {}", entry.src, entry.dest, e));
+                match lock.entry(&entry.src, &entry.dest) {
+                    Some(previous) => previous.hash.clone(),
+                    None => continue,
+                }
+            }
+        };
+        synced.clone.push(LockEntry { src: entry.src.clone(), dest: entry.dest.clone(), hash });
+    }
+
+    for entry in &manifest.compose {
+        let pins = entry.layers.iter()
+            .map(|layer| lock.hash(&layer.src, &entry.dest).filter(|_| !update).map(str::to_owned))
+            .collect::<Vec<_>>();
+        let options = RegitOptions {
+            conflict: entry.conflict.unwrap_or(ConflictStrategy::Overwrite),
+            patches: entry.patches.iter().map(|p| dir.join(p).as_str().to_owned()).collect(),
+            stash: false,
+            interactive: false,
+            ..options.clone()
+        };
+        let dest = dir.join(&entry.dest);
+        match Regit::compose(&entry.layers, &pins, dest.as_str(), options, entry.layer_conflict, cache.clone()).await {
+            Ok(hashes) => for (layer, hash) in entry.layers.iter().zip(hashes) {
+                synced.clone.push(LockEntry { src: layer.src.clone(), dest: entry.dest.clone(), hash });
+            },
+            Err(e) => {
+                errors.push(format!("{} layers → {}: {}", entry.layers.len(), entry.dest, e));
+                let previous = entry.layers.iter().filter_map(|layer| lock.entry(&layer.src, &entry.dest));
+                synced.clone.extend(previous.cloned());
+            },
+        }
+    }
+
+    if !options.dry_run {
+        synced.save(dir)?;
+    }
+    match errors.is_empty() {
+        true => {
+            done!(format!("Synced {} templates", synced.clone.len()));
+            Ok(synced)
+        },
+        false => Err(format!("{} of {} templates failed to sync:\n  {}", errors.len(), total, errors.join("\n  "))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, provenance::Provenance, test_util::{commit, git}};
+
+    #[test]
+    fn rejects_interactive_entries() {
+        assert!(Manifest::parse("[[clone]]\nsrc = \"a/b\"\ndest = \"x\"\nconflict = \"interactive\"").is_err());
+        assert!(Manifest::parse("[[clone]]\nsrc = \"a/b\"\ndest = \"x\"\nmode = \"local\"").is_err());
+        assert!(Manifest::parse("[[clone]]\nsrc = \"a/b\"").is_err(), "dest is required");
+        let manifest = Manifest::parse("[[clone]]\nsrc = \"a/b\"\ndest = \"x\"\nconflict = \"merge\"").unwrap();
+        assert_eq!(manifest.clone[0].conflict, Some(ConflictStrategy::Merge));
+
+        let manifest = Manifest::parse("[[compose]]\ndest = \"x\"\nlayer_conflict = \"error\"\nlayers = [{ src = \"a/b\", subdir = \"auth\", include = [\"src/**\"] }]").unwrap();
+        assert_eq!(manifest.compose[0].layer_conflict, LayerConflict::Error);
+        assert_eq!(manifest.compose[0].layers[0].sub_dir.as_deref(), Some("auth"));
+        assert!(Manifest::parse("[[compose]]\ndest = \"x\"\nlayers = []").is_err());
+    }
+
+    #[test]
+    fn rejects_shared_destinations() {
+        let clones = "[[clone]]\nsrc = \"a/b\"\ndest = \"x\"\n[[clone]]\nsrc = \"c/d\"\ndest = \"./x/\"";
+        assert!(Manifest::parse(clones).unwrap_err().contains("'./x/'"));
+        let mixed = "[[clone]]\nsrc = \"a/b\"\ndest = \".\"\n[[compose]]\ndest = \"\"\nlayers = [{ src = \"c/d\" }]";
+        assert!(Manifest::parse(mixed).is_err());
+        assert!(Manifest::parse("[[clone]]\nsrc = \"a/b\"\ndest = \"x\"\n[[clone]]\nsrc = \"a/b\"\ndest = \"x/y\"").is_ok());
+    }
+
+    #[test]
+    fn rejects_destinations_outside_project() {
+        assert!(Manifest::parse("[[clone]]\nsrc = \"a/b\"\ndest = \"/etc\"").is_err());
+        assert!(Manifest::parse("[[clone]]\nsrc = \"a/b\"\ndest = \"x/../../y\"").is_err());
+        assert!(Manifest::parse("[[compose]]\ndest = \"..\"\nlayers = [{ src = \"a/b\" }]").is_err());
+        assert!(Manifest::parse("[[clone]]\nsrc = \"a/b\"\ndest = \"./x/y\"").is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn syncs_manifest_and_honors_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let upstream = tmp.path().join("upstream");
+        crate::util::mkdirp(&upstream);
+        git(&upstream, &["init", "-q", "-b", "main"]);
+        let first = commit(&upstream, &[("ci/build.yml", "v1"), ("lint/rules.toml", "v1")]);
+
+        let project = tmp.path().join("project");
+        crate::util::mkdirp(&project);
+        fs::write(project.join(MANIFEST_FILE), format!(
+            "[[clone]]\nsrc = \"ci\"\ndest = \".github\"\n\n[[clone]]\nsrc = \"{0}/lint#main\"\ndest = \"lint\"\n",
+            upstream.as_str(),
+        )).unwrap();
+        let config = Config::parse(&format!("[aliases]\nci = \"{}/ci#main\"", upstream.as_str())).unwrap();
+        let options = RegitOptions { cache_dir: Some(tmp.path().join("cache").as_str().into()), config, ..Default::default() };
+
+        let lock = sync(&project, options.clone(), false).await.unwrap();
+        assert_eq!(lock.hash("ci", ".github"), Some(first.as_str()));
+        let provenance = Provenance::load(&project.join(".github")).unwrap();
+        assert_eq!((provenance.source.as_str(), provenance.resolved_source()), ("ci", format!("{}/ci#main", upstream.as_str()).as_str()));
+        assert_eq!(fs::read_to_string(project.join(".github/build.yml")).unwrap(), "v1");
+        assert_eq!(fs::read_to_string(project.join("lint/rules.toml")).unwrap(), "v1");
+        assert_eq!(Lock::load(&project).unwrap(), lock);
+
+        let second = commit(&upstream, &[("ci/build.yml", "v2"), ("lint/rules.toml", "v2")]);
+        sync(&project, options.clone(), false).await.unwrap();
+        assert_eq!(fs::read_to_string(project.join(".github/build.yml")).unwrap(), "v1", "the lock should pin the commit");
+
+        let lock = sync(&project, options, true).await.unwrap();
+        assert_eq!(lock.hash("ci", ".github"), Some(second.as_str()));
+        assert_eq!(fs::read_to_string(project.join(".github/build.yml")).unwrap(), "v2");
+        assert_eq!(fs::read_to_string(project.join("lint/rules.toml")).unwrap(), "v2");
+    }
+}
@@ -0,0 +1,225 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use crate::{actions::ACTIONS_FILE, options::MergeOutcome, provenance::PROVENANCE_FILE, util::{copy_path, mkdirp}};
+
+/// Applies the changes between two versions of a template, `base` and
+/// `theirs`, to the directory scaffolded from it, keeping local edits.
+/// Text files changed on both sides are merged line by line; overlapping
+/// changes get conflict markers, or with `reject` are left alone and the
+/// upstream diff is written next to them as `<file>.rej`.
+pub fn merge_trees(base: &Path, theirs: &Path, ours: &Path, reject: bool) -> Result<Vec<(String, MergeOutcome)>, String> {
+    let mut files = list_files(base)?;
+    files.extend(list_files(theirs)?);
+    files.remove(PROVENANCE_FILE);
+    files.remove(ACTIONS_FILE);
+
+    let mut outcomes = vec![];
+    for file in files {
+        let (b, t, o) = (read(&base.join(&file)), read(&theirs.join(&file)), read(&ours.join(&file)));
+        if b == t || (t.is_some() && o == t) { continue }
+
+        let target = ours.join(&file);
+        let outcome = match (b, t, o) {
+            (None, Some(_), None) => {
+                replace(&theirs.join(&file), &target)?;
+                MergeOutcome::Added
+            },
+            (Some(b), Some(_), Some(o)) if o == b => {
+                replace(&theirs.join(&file), &target)?;
+                MergeOutcome::Updated
+            },
+            (Some(b), None, Some(o)) if o == b => {
+                fs::remove_file(&target).map_err(|e| format!("can't remove '{}': {}", file, e))?;
+                MergeOutcome::Deleted
+            },
+            (_, None, None) => continue,
+            (_, None, Some(_)) => MergeOutcome::Kept(String::from("removed upstream, edited locally")),
+            (Some(_), Some(_), None) => MergeOutcome::Kept(String::from("removed locally")),
+            (b, Some(t), Some(o)) => {
+                let b = b.unwrap_or_default();
+                let (Ok(b), Ok(t), Ok(o)) = (String::from_utf8(b), String::from_utf8(t), String::from_utf8(o)) else {
+                    outcomes.push((file, MergeOutcome::Kept(String::from("binary file changed on both sides"))));
+                    continue;
+                };
+                match diffy::merge(&b, &o, &t) {
+                    Ok(merged) => {
+                        fs::write(&target, merged).map_err(|e| e.to_string())?;
+                        MergeOutcome::Merged
+                    },
+                    Err(_) if reject => {
+                        let rej = format!("{}.rej", file);
+                        fs::write(ours.join(&rej), diffy::create_patch(&b, &t).to_string()).map_err(|e| e.to_string())?;
+                        MergeOutcome::Rejected(rej)
+                    },
+                    Err(conflicted) => {
+                        fs::write(&target, conflicted).map_err(|e| e.to_string())?;
+                        MergeOutcome::Conflict
+                    },
+                }
+            },
+        };
+        outcomes.push((file, outcome));
+    }
+    Ok(outcomes)
+}
+
+/// Unified diff of every file that differs between `old` and `new`. With
+/// `only_old`, files that only exist in `new` are left out.
+pub fn diff_trees(old: &Path, new: &Path, only_old: bool) -> Result<String, String> {
+    let mut files = list_files(old)?;
+    if !only_old { files.extend(list_files(new)?) }
+    files.remove(PROVENANCE_FILE);
+    files.remove(ACTIONS_FILE);
+
+    let mut diff = String::new();
+    for file in files {
+        let (a, b) = (read(&old.join(&file)), read(&new.join(&file)));
+        if a == b { continue }
+        let original = a.as_ref().map_or(String::from("/dev/null"), |_| format!("a/{}", file));
+        let modified = b.as_ref().map_or(String::from("/dev/null"), |_| format!("b/{}", file));
+
+        match (String::from_utf8(a.unwrap_or_default()), String::from_utf8(b.unwrap_or_default())) {
+            (Ok(a), Ok(b)) => {
+                let patch = diffy::DiffOptions::new()
+                    .set_original_filename(original)
+                    .set_modified_filename(modified)
+                    .create_patch(&a, &b)
+                    .to_string();
+                diff.push_str(&patch);
+            },
+            _ => diff.push_str(&format!("Binary files {} and {} differ\n", original, modified)),
+        }
+    }
+    Ok(diff)
+}
+
+/// Relative paths of all files and symlinks under `root`.
+pub(crate) fn list_files(root: &Path) -> Result<BTreeSet<String>, String> {
+    fn walk(root: &Path, dir: &Path, files: &mut BTreeSet<String>) -> Result<(), String> {
+        for entry in fs::read_dir(dir).map_err(|e| format!("can't read '{}': {}", dir.display(), e))? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.is_dir() && !path.is_symlink() {
+                walk(root, &path, files)?;
+            } else {
+                files.insert(path.strip_prefix(root).unwrap().to_string_lossy().into_owned());
+            }
+        }
+        Ok(())
+    }
+    let mut files = BTreeSet::new();
+    if root.is_dir() { walk(root, root, &mut files)? }
+    Ok(files)
+}
+
+/// Contents of a file, or the target of a symlink.
+pub(crate) fn read(path: &Path) -> Option<Vec<u8>> {
+    match fs::read_link(path) {
+        Ok(target) => Some(target.to_string_lossy().as_bytes().to_vec()),
+        Err(_) => fs::read(path).ok(),
+    }
+}
+
+/// Copies `from` over `to`, creating its parent directories.
+pub(crate) fn replace(from: &Path, to: &Path) -> Result<(), String> {
+    if let Some(dir) = to.parent() { mkdirp(dir) }
+    if to.symlink_metadata().is_ok() {
+        fs::remove_file(to).map_err(|e| e.to_string())?;
+    }
+    copy_path(from, to).map_err(|e| format!("can't write '{}': {}", to.display(), e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(root: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            mkdirp(root.join(path).parent().unwrap());
+            fs::write(root.join(path), contents).unwrap();
+        }
+    }
+
+    fn versions(tmp: &Path) -> (std::path::PathBuf, std::path::PathBuf, std::path::PathBuf) {
+        let (base, theirs, ours) = (tmp.join("base"), tmp.join("theirs"), tmp.join("ours"));
+        tree(&base, &[
+            ("same.txt", "same"),
+            ("updated.txt", "v1"),
+            ("merged.txt", "a\nb\nc\nd\ne\n"),
+            ("conflict.txt", "a\nb\nc\n"),
+            ("deleted.txt", "gone"),
+            ("edited-deleted.txt", "v1"),
+            ("src/lib.rs", "v1"),
+        ]);
+        tree(&theirs, &[
+            ("same.txt", "same"),
+            ("updated.txt", "v2"),
+            ("merged.txt", "a\nb\nc\nd\nE\n"),
+            ("conflict.txt", "a\nB\nc\n"),
+            ("added/new.txt", "new"),
+            ("src/lib.rs", "v2"),
+        ]);
+        tree(&ours, &[
+            ("same.txt", "mine"),
+            ("updated.txt", "v1"),
+            ("merged.txt", "A\nb\nc\nd\ne\n"),
+            ("conflict.txt", "a\nmine\nc\n"),
+            ("deleted.txt", "gone"),
+            ("edited-deleted.txt", "mine"),
+        ]);
+        (base, theirs, ours)
+    }
+
+    #[test]
+    fn merges_upstream_changes_with_local_edits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (base, theirs, ours) = versions(tmp.path());
+
+        let outcomes = merge_trees(&base, &theirs, &ours, false).unwrap();
+        assert_eq!(outcomes, vec![
+            ("added/new.txt".to_owned(), MergeOutcome::Added),
+            ("conflict.txt".to_owned(), MergeOutcome::Conflict),
+            ("deleted.txt".to_owned(), MergeOutcome::Deleted),
+            ("edited-deleted.txt".to_owned(), MergeOutcome::Kept("removed upstream, edited locally".into())),
+            ("merged.txt".to_owned(), MergeOutcome::Merged),
+            ("src/lib.rs".to_owned(), MergeOutcome::Kept("removed locally".into())),
+            ("updated.txt".to_owned(), MergeOutcome::Updated),
+        ]);
+        let read = |file: &str| fs::read_to_string(ours.join(file)).unwrap();
+        assert_eq!(read("same.txt"), "mine", "files unchanged upstream should be left alone");
+        assert_eq!(read("updated.txt"), "v2");
+        assert_eq!(read("merged.txt"), "A\nb\nc\nd\nE\n");
+        assert!(read("conflict.txt").contains("<<<<<<<"));
+        assert!(!ours.join("deleted.txt").exists());
+        assert_eq!(read("added/new.txt"), "new");
+    }
+
+    #[test]
+    fn writes_rejects_instead_of_markers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (base, theirs, ours) = versions(tmp.path());
+
+        let outcomes = merge_trees(&base, &theirs, &ours, true).unwrap();
+        assert!(outcomes.contains(&("conflict.txt".to_owned(), MergeOutcome::Rejected("conflict.txt.rej".into()))));
+        assert_eq!(fs::read_to_string(ours.join("conflict.txt")).unwrap(), "a\nmine\nc\n");
+        let rej = fs::read_to_string(ours.join("conflict.txt.rej")).unwrap();
+        assert!(rej.contains("-b\n+B\n"), "should hold the upstream hunk: {}", rej);
+    }
+
+    #[test]
+    fn diffs_trees() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (base, theirs, ours) = versions(tmp.path());
+        tree(&ours, &[("local-only.txt", "mine")]);
+
+        let diff = diff_trees(&base, &ours, true).unwrap();
+        assert!(diff.contains("--- a/conflict.txt\n+++ b/conflict.txt\n"), "{}", diff);
+        assert!(diff.contains("-b\n+mine\n"));
+        assert!(diff.contains("--- a/src/lib.rs\n+++ /dev/null\n"), "locally removed files should show");
+        assert!(!diff.contains("local-only.txt"), "files outside the template should be left out");
+        assert!(!diff.contains("updated.txt"), "unchanged files should be left out");
+
+        let diff = diff_trees(&base, &theirs, false).unwrap();
+        assert!(diff.contains("--- /dev/null\n+++ b/added/new.txt\n"));
+    }
+}
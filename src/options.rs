@@ -1,12 +1,175 @@
+/// Kind of server hosting a repository, which decides its archive URLs and
+/// how paths on it are read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum GitServer {
     GitHub,
     GitLab,
     BitBucket,
+    SourceHut,
 }
 
-#[derive(Default, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, PartialOrd, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ValidModes {
     #[default]
     Tar,
     Git,
-}
\ No newline at end of file
+    /// Plain archive URL or local archive file, no git host involved.
+    Archive,
+    /// Local directory, or a local git repository when a ref is given.
+    Local,
+}
+
+/// What to do when an extracted file already exists in the destination.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStrategy {
+    /// Refuse to clone into a non-empty destination.
+    #[default]
+    Abort,
+    /// Only write files that don't exist yet.
+    Merge,
+    /// Replace existing files.
+    Overwrite,
+    /// Move existing files aside before writing.
+    Backup,
+    /// Ask for every clashing file.
+    Interactive,
+}
+
+/// Outcome of applying a template change to a single file during an update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// Added upstream.
+    Added,
+    /// Changed upstream and untouched locally.
+    Updated,
+    /// Removed upstream and untouched locally.
+    Deleted,
+    /// Changed on both sides without overlapping.
+    Merged,
+    /// Changed on both sides, written with conflict markers.
+    Conflict,
+    /// Changed on both sides, upstream changes written to the given `.rej` file.
+    Rejected(String),
+    /// Changed upstream but kept as is locally, for the given reason.
+    Kept(String),
+}
+
+/// Outcome of extracting a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileOutcome {
+    Created,
+    Overwritten,
+    Skipped,
+    BackedUp(String),
+    /// The file exists and the strategy needs a decision (dry run only).
+    Conflict,
+}
+
+/// Modification time given to extracted files.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MtimePolicy {
+    /// Keep each entry's mtime from the archive.
+    #[default]
+    Preserve,
+    /// Stamp every file with the commit time.
+    Commit,
+    /// Stamp every file with the current time.
+    Now,
+}
+impl MtimePolicy {
+    pub(crate) fn resolve(&self, entry_mtime: u64, commit_time: u64) -> std::time::SystemTime {
+        let secs = |s| std::time::UNIX_EPOCH + std::time::Duration::from_secs(s);
+        match self {
+            MtimePolicy::Preserve => secs(entry_mtime),
+            MtimePolicy::Commit => secs(commit_time),
+            MtimePolicy::Now => std::time::SystemTime::now(),
+        }
+    }
+}
+
+/// What to do when several layers of a composition ship the same file.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerConflict {
+    /// The later layer wins.
+    #[default]
+    Precedence,
+    /// Refuse to compose when layers ship differing versions of a file.
+    Error,
+}
+
+/// One source of a layered composition, applied over the ones before it.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub src: String,
+    /// Subdirectory to take from the source, instead of the one in `src`.
+    #[serde(rename = "subdir")]
+    pub sub_dir: Option<String>,
+    /// Globs of files to take, all of them when empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs of files to leave out.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Archive format the selected files are written as instead of a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Tar,
+    #[value(name = "tar.gz")]
+    TarGz,
+    Zip,
+}
+impl OutputFormat {
+    /// Format matching the extension of `path`.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let path = path.to_lowercase();
+        if path.ends_with(".tar.gz") || path.ends_with(".tgz") { Some(Self::TarGz) }
+        else if path.ends_with(".tar") { Some(Self::Tar) }
+        else if path.ends_with(".zip") { Some(Self::Zip) }
+        else { None }
+    }
+}
+
+/// Where and how to write the selected files as an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub format: OutputFormat,
+    /// File to write, `-` for stdout.
+    pub path: String,
+}
+impl Output {
+    pub fn is_stdout(&self) -> bool {
+        self.path == "-"
+    }
+}
+
+/// How `--git-init` sets up the destination's repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitInit {
+    /// Initial branch, git's `init.defaultBranch` when unset.
+    pub branch: Option<String>,
+    /// Subject of the initial commit, followed by the template source.
+    pub message: String,
+    /// Committer name and email, git's `user.name` and `user.email` when unset.
+    pub identity: Option<(String, String)>,
+}
+impl Default for GitInit {
+    fn default() -> Self {
+        Self { branch: None, message: String::from("Initial commit from template"), identity: None }
+    }
+}
+
+/// What `--keep-git` leaves in the destination in git mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeepGit {
+    /// The repository's full history.
+    Full,
+    /// Only the history of the subdirectory, rewritten with `git subtree split`.
+    Subtree,
+}
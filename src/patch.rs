@@ -0,0 +1,291 @@
+use std::{fs, path::Path};
+
+use regex::Regex;
+
+use crate::{app::safe_join, util::{mkdirp, refuse_symlink}};
+
+/// Context lines a hunk may lose at either end and still apply, like
+/// `patch --fuzz=2`.
+pub const DEFAULT_FUZZ: usize = 2;
+
+/// Changes to a single file from a unified diff.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilePatch {
+    /// Path before the change, `None` for created files.
+    pub old: Option<String>,
+    /// Path after the change, `None` for deleted files.
+    pub new: Option<String>,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Hunk {
+    /// First line of the hunk in the original file, counting from 1.
+    old_start: usize,
+    lines: Vec<Line>,
+    /// The hunk as written in the diff, for `.rej` files.
+    text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+/// How a patch went for one file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchResult {
+    pub file: String,
+    pub applied: usize,
+    /// Hunks that only applied after dropping context lines.
+    pub fuzzed: usize,
+    pub rejected: usize,
+    /// `.rej` file holding the rejected hunks, relative to the root.
+    pub rej: Option<String>,
+}
+
+impl FilePatch {
+    /// File the patch applies to, relative to the patched tree.
+    pub fn path(&self) -> &str {
+        self.new.as_deref().or(self.old.as_deref()).unwrap_or_default()
+    }
+}
+
+/// Parses every file section of a unified diff, as written by `diff -u`,
+/// `git diff` or `regit diff`. Text around the sections is ignored.
+pub fn parse(diff: &str) -> Result<Vec<FilePatch>, String> {
+    let header = Regex::new(r"^@@ -(\d+)(?:,(\d+))? \+(\d+)(?:,(\d+))? @@").unwrap();
+    let path = |line: &str| {
+        let path = line[4..].split('\t').next().unwrap_or_default().trim_end();
+        match path {
+            "/dev/null" => None,
+            path => Some(path.strip_prefix("a/").or(path.strip_prefix("b/")).unwrap_or(path).to_owned()),
+        }
+    };
+
+    let lines = diff.split_inclusive('\n').collect::<Vec<_>>();
+    let mut patches = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        if !(lines[i].starts_with("--- ") && lines.get(i + 1).is_some_and(|l| l.starts_with("+++ "))) {
+            i += 1;
+            continue;
+        }
+        let mut patch = FilePatch { old: path(lines[i]), new: path(lines[i + 1]), hunks: vec![] };
+        i += 2;
+        while let Some(caps) = lines.get(i).and_then(|l| header.captures(l)) {
+            let count = |n: usize| caps.get(n).map_or(Ok(1), |m| m.as_str().parse::<usize>()).map_err(|e| e.to_string());
+            let (mut old_left, mut new_left) = (count(2)?, count(4)?);
+            let old_start = caps[1].parse::<usize>().map_err(|e| e.to_string())?;
+            let mut hunk = Hunk { old_start, lines: vec![], text: lines[i].to_owned() };
+            i += 1;
+            while old_left > 0 || new_left > 0 || lines.get(i).is_some_and(|l| l.starts_with('\\')) {
+                let Some(line) = lines.get(i) else {
+                    return Err(format!("hunk of '{}' ends early", patch.path()));
+                };
+                hunk.text.push_str(line);
+                i += 1;
+                if !line.is_char_boundary(1) {
+                    return Err(format!("unexpected line in hunk of '{}': {}", patch.path(), line.trim_end()));
+                }
+                let (kind, content) = line.split_at(1);
+                let content = if content.is_empty() && kind == "\n" { "\n" } else { content };
+                match kind {
+                    " " | "\n" => { old_left = old_left.saturating_sub(1); new_left = new_left.saturating_sub(1); hunk.lines.push(Line::Context(content.into())) },
+                    "-" => { old_left = old_left.saturating_sub(1); hunk.lines.push(Line::Remove(content.into())) },
+                    "+" => { new_left = new_left.saturating_sub(1); hunk.lines.push(Line::Add(content.into())) },
+                    // "\ No newline at end of file" applies to the line before.
+                    "\\" => match hunk.lines.last_mut() {
+                        Some(Line::Context(l) | Line::Remove(l) | Line::Add(l)) => { l.pop(); },
+                        None => {},
+                    },
+                    _ => return Err(format!("unexpected line in hunk of '{}': {}", patch.path(), line.trim_end())),
+                }
+            }
+            patch.hunks.push(hunk);
+        }
+        patches.push(patch);
+    }
+    if patches.is_empty() {
+        return Err(String::from("no file changes found, expected a unified diff"));
+    }
+    Ok(patches)
+}
+
+/// Applies `patches` to the tree at `root`. Hunks are looked for at their
+/// recorded line first and then further and further away; hunks that still
+/// don't apply are retried with up to `fuzz` context lines dropped at either
+/// end. Rejected hunks are written next to their file as `<file>.rej`.
+pub fn apply(patches: &[FilePatch], root: &Path, fuzz: usize) -> Result<Vec<PatchResult>, String> {
+    let mut results = vec![];
+    for patch in patches {
+        let file = patch.path();
+        let path = safe_join(root, file).ok_or_else(|| format!("'{}' points outside of the destination", file))?;
+        refuse_symlink(&path, file)?;
+        let original = match patch.old {
+            Some(_) => fs::read_to_string(&path).map_err(|e| format!("can't read '{}': {}", file, e))?,
+            None => String::new(),
+        };
+        let mut lines = original.split_inclusive('\n').map(str::to_owned).collect::<Vec<_>>();
+
+        let mut result = PatchResult { file: file.to_owned(), applied: 0, fuzzed: 0, rejected: 0, rej: None };
+        let mut rejects = String::new();
+        let (mut offset, mut floor) = (0isize, 0usize);
+        for hunk in &patch.hunks {
+            match (0..=fuzz).find_map(|f| place(hunk, &lines, f, offset, floor).map(|p| (f, p))) {
+                Some((f, (at, old, new))) => {
+                    // Lines after the hunk move by what it found plus what it adds.
+                    offset = at as isize - original_line(hunk, f) as isize + new.len() as isize - old as isize;
+                    floor = at + new.len();
+                    lines.splice(at..at + old, new);
+                    result.applied += 1;
+                    if f > 0 { result.fuzzed += 1 }
+                },
+                None => {
+                    result.rejected += 1;
+                    rejects.push_str(&hunk.text);
+                },
+            }
+        }
+
+        if !rejects.is_empty() {
+            let rej = format!("{}.rej", file);
+            let header = format!("--- {}\n+++ {}\n", patch.old.as_deref().unwrap_or("/dev/null"), patch.new.as_deref().unwrap_or("/dev/null"));
+            let rej_path = root.join(&rej);
+            refuse_symlink(&rej_path, &rej)?;
+            if let Some(dir) = rej_path.parent() { mkdirp(dir) }
+            fs::write(&rej_path, header + &rejects).map_err(|e| format!("can't write '{}': {}", rej, e))?;
+            result.rej = Some(rej);
+        }
+        if result.applied > 0 || patch.hunks.is_empty() {
+            let contents = lines.concat();
+            if patch.new.is_none() && contents.is_empty() {
+                fs::remove_file(&path).map_err(|e| format!("can't remove '{}': {}", file, e))?;
+            } else {
+                if let Some(dir) = path.parent() { mkdirp(dir) }
+                fs::write(&path, contents).map_err(|e| format!("can't write '{}': {}", file, e))?;
+            }
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Index in the original file of the first line `hunk` matches with `fuzz`
+/// context lines dropped.
+fn original_line(hunk: &Hunk, fuzz: usize) -> usize {
+    let leading = hunk.lines.iter().take_while(|l| matches!(l, Line::Context(_))).count().min(fuzz);
+    let removes = hunk.lines.iter().any(|l| !matches!(l, Line::Add(_)));
+    // An empty range in a hunk header counts the line before it.
+    let start = if removes { hunk.old_start.saturating_sub(1) } else { hunk.old_start };
+    start + leading
+}
+
+/// Where `hunk` applies with `fuzz` context lines dropped: the index to
+/// splice at, the number of lines it replaces and the lines replacing them.
+fn place(hunk: &Hunk, lines: &[String], fuzz: usize, offset: isize, floor: usize) -> Option<(usize, usize, Vec<String>)> {
+    let leading = hunk.lines.iter().take_while(|l| matches!(l, Line::Context(_))).count().min(fuzz);
+    let trailing = hunk.lines.iter().rev().take_while(|l| matches!(l, Line::Context(_))).count().min(fuzz);
+    let body = &hunk.lines[leading..hunk.lines.len().saturating_sub(trailing).max(leading)];
+
+    let old = body.iter().filter_map(|l| match l { Line::Context(s) | Line::Remove(s) => Some(s.as_str()), _ => None }).collect::<Vec<_>>();
+    let new = body.iter().filter_map(|l| match l { Line::Context(s) | Line::Add(s) => Some(s.clone()), _ => None }).collect::<Vec<_>>();
+    let expected = original_line(hunk, fuzz) as isize + offset;
+
+    let last = lines.len().checked_sub(old.len())?;
+    let fits = |at: usize| at >= floor && at <= last && lines[at..at + old.len()].iter().zip(&old).all(|(a, b)| a == b);
+    (0..=lines.len() as isize)
+        .flat_map(|distance| [expected - distance, expected + distance])
+        .filter(|at| *at >= 0)
+        .map(|at| at as usize)
+        .find(|at| fits(*at))
+        .map(|at| (at, old.len(), new))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = "\
+diff --git a/src/main.rs b/src/main.rs
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,4 +1,4 @@
+ fn main() {
+-    println!(\"hello\");
++    println!(\"hello, patched\");
+ }
+ // end
+@@ -8,3 +8,4 @@
+ a
+ b
+ c
++d
+--- /dev/null
++++ b/NOTES.md
+@@ -0,0 +1 @@
++notes
+\\ No newline at end of file
+";
+
+    fn tree(root: &Path, main: &str) {
+        mkdirp(&root.join("src"));
+        fs::write(root.join("src/main.rs"), main).unwrap();
+    }
+
+    #[test]
+    fn parses_unified_diffs() {
+        let patches = parse(DIFF).unwrap();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].path(), "src/main.rs");
+        assert_eq!(patches[0].hunks.len(), 2);
+        assert_eq!((patches[1].old.as_deref(), patches[1].path()), (None, "NOTES.md"));
+        assert_eq!(patches[1].hunks[0].lines, vec![Line::Add("notes".into())]);
+        assert!(parse("not a diff").is_err());
+    }
+
+    #[test]
+    fn applies_with_offset_and_fuzz() {
+        let tmp = tempfile::tempdir().unwrap();
+        // Two lines were added on top, and the context of the second hunk changed.
+        tree(tmp.path(), "// header\n\nfn main() {\n    println!(\"hello\");\n}\n// end\n\n\nx\nb\nc\n");
+
+        let results = apply(&parse(DIFF).unwrap(), tmp.path(), DEFAULT_FUZZ).unwrap();
+        assert_eq!(results[0], PatchResult { file: "src/main.rs".into(), applied: 2, fuzzed: 1, rejected: 0, rej: None });
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("src/main.rs")).unwrap(),
+            "// header\n\nfn main() {\n    println!(\"hello, patched\");\n}\n// end\n\n\nx\nb\nc\nd\n",
+        );
+        assert_eq!(fs::read_to_string(tmp.path().join("NOTES.md")).unwrap(), "notes");
+    }
+
+    #[test]
+    fn rejects_hunks_that_dont_apply() {
+        let tmp = tempfile::tempdir().unwrap();
+        tree(tmp.path(), "fn main() {\n    println!(\"bye\");\n}\n// end\n\n\n\na\nb\nc\n");
+
+        let results = apply(&parse(DIFF).unwrap(), tmp.path(), 0).unwrap();
+        assert_eq!((results[0].applied, results[0].rejected), (1, 1));
+        assert_eq!(results[0].rej.as_deref(), Some("src/main.rs.rej"));
+        let rej = fs::read_to_string(tmp.path().join("src/main.rs.rej")).unwrap();
+        assert!(rej.starts_with("--- src/main.rs\n+++ src/main.rs\n@@ -1,4 +1,4 @@\n"), "{}", rej);
+        assert!(fs::read_to_string(tmp.path().join("src/main.rs")).unwrap().ends_with("c\nd\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_patching_through_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (root, outside) = (tmp.path().join("dest"), tmp.path().join("outside.rs"));
+        mkdirp(&root.join("src"));
+        let original = "fn main() {\n    println!(\"hello\");\n}\n// end\n";
+        fs::write(&outside, original).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("src/main.rs")).unwrap();
+
+        assert!(apply(&parse(DIFF).unwrap(), &root, DEFAULT_FUZZ).is_err());
+        assert_eq!(fs::read_to_string(&outside).unwrap(), original);
+    }
+}
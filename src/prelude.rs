@@ -0,0 +1 @@
+pub use crate::{app::{Regit, RegitOptions}, cache::Cache, config::Config};
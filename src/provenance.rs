@@ -0,0 +1,107 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+pub const PROVENANCE_FILE: &str = ".regit.json";
+
+/// Where a scaffolded directory came from, recorded in its `.regit.json` so
+/// `regit info` can tell and `regit update` can find the template version it
+/// started from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Source as given on the command line, aliases included.
+    pub source: String,
+    /// `source` with aliases expanded, what was actually cloned. Empty in
+    /// files written before it was recorded, whose `source` is expanded.
+    #[serde(default)]
+    pub resolved: String,
+    /// Normalized repository or archive URL, or local path.
+    pub url: String,
+    #[serde(default)]
+    pub sub_dir: String,
+    /// Requested ref, empty for archives and local directories.
+    #[serde(rename = "ref", default)]
+    pub _ref: String,
+    /// Resolved commit, none for archives and local directories.
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// SHA-256 of the extracted archive, none for local directories.
+    #[serde(default)]
+    pub archive_sha256: Option<String>,
+    /// Version of regit that wrote the file.
+    #[serde(default)]
+    pub version: String,
+    /// Seconds since the Unix epoch.
+    #[serde(default)]
+    pub timestamp: u64,
+    /// Template variables the files were rendered with.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
+    /// Patches applied on top, relative to the directory unless absolute.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<String>,
+}
+impl Provenance {
+    pub fn load(dest: &Path) -> Result<Self, String> {
+        let path = dest.join(PROVENANCE_FILE);
+        let file = fs::File::open(&path)
+            .map_err(|_| format!("'{}' wasn't scaffolded by regit, '{}' is missing", dest.display(), PROVENANCE_FILE))?;
+        serde_json::from_reader(file).map_err(|e| format!("invalid '{}': {}", path.display(), e))
+    }
+
+    pub fn save(&self, dest: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(dest.join(PROVENANCE_FILE), contents + "\n").map_err(|e| e.to_string())
+    }
+
+    /// The source to clone again, unaffected by aliases changed since.
+    pub fn resolved_source(&self) -> &str {
+        match self.resolved.is_empty() {
+            true => &self.source,
+            false => &self.resolved,
+        }
+    }
+
+    /// What to pin the source to in order to get the recorded files back.
+    pub fn pin(&self) -> Option<&str> {
+        self.commit.as_deref().or(self.archive_sha256.as_deref())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_and_loads_provenance() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(Provenance::load(tmp.path()).is_err());
+
+        let provenance = Provenance {
+            source: "tpl/sub#main".into(),
+            resolved: "user/repo/sub#main".into(),
+            url: "https://github.com/user/repo".into(),
+            sub_dir: "sub".into(),
+            _ref: "main".into(),
+            commit: Some("abc".into()),
+            archive_sha256: Some("def".into()),
+            version: "0.1.0".into(),
+            timestamp: 1_600_000_000,
+            vars: BTreeMap::from([("project_name".into(), "demo".into())]),
+            patches: vec!["patches/readme.diff".into()],
+        };
+        provenance.save(tmp.path()).unwrap();
+        assert_eq!(Provenance::load(tmp.path()).unwrap(), provenance);
+        assert!(fs::read_to_string(tmp.path().join(PROVENANCE_FILE)).unwrap().contains("\"ref\": \"main\""));
+        assert_eq!(provenance.pin(), Some("abc"));
+        assert_eq!(provenance.resolved_source(), "user/repo/sub#main");
+    }
+
+    #[test]
+    fn pins_archives_by_checksum() {
+        let provenance: Provenance = serde_json::from_str(r#"{"source": "x.zip", "url": "x.zip", "archive_sha256": "def"}"#).unwrap();
+        assert_eq!(provenance.pin(), Some("def"));
+        assert_eq!(provenance.resolved_source(), "x.zip", "older files only have the expanded source");
+    }
+}
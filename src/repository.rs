@@ -1,15 +1,27 @@
-use std::{collections::HashMap, process, str};
+use std::{collections::HashMap, path::{Path, PathBuf}, str};
 
 use super::*;
 use regex::Regex;
 
-use crate::{options::ValidModes, cache::Cache};
+use crate::{options::{GitServer, ValidModes}, cache::Cache, config::{self, Config}, util::{self, git_remote, sha256_hex, token_for}};
 
-const SUPPORTED_DOMAINS: [&'static str; 4] = ["github.com", "gitlab.com", "bitbucket.org", "git.sr.ht"];
-const RE_VALID_REPO: &'static str = r"^(?:(?:https://)?([^:/]+\.[^:/]+)/|git@([^:/]+)[:/]|([^/]+):)?([^/\s]+)/([^/\s#]+)(?:((?:/[^/\s#]+)+))?(?:/)?(?:#(.+))?";
+const RE_VALID_REPO: &str = r"^(?:(?:https://)?([^:/]+\.[^:/]+)/|git@([^:/]+)[:/]|([^/]+):)?([^/\s]+)/([^/\s#]+)(?:((?:/[^/\s#]+)+))?(?:/)?(?:#(.+))?";
+
+const ARCHIVE_EXTENSIONS: [&str; 10] = [
+    ".tar.gz", ".tgz", ".tar.bz2", ".tbz2", ".tar.xz", ".txz", ".tar.zst", ".tzst", ".tar", ".zip",
+];
 
 type HashCache = HashMap<String, String>;
 
+pub(crate) fn has_archive_ext(path: &str) -> bool {
+    ARCHIVE_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+/// Whether `_ref` is a full commit hash, which needs no resolving.
+pub(crate) fn is_commit_hash(_ref: &str) -> bool {
+    _ref.len() == 40 && _ref.chars().all(|c| c.is_ascii_hexdigit())
+}
+
 #[derive(Default, Debug, Clone)]
 struct Ref {
     kind: String,
@@ -34,27 +46,50 @@ impl Ref {
 pub struct Repository {
     pub url: String,
     pub domain: String,
+    /// Owner namespace, e.g. `user` or `group/subgroup` on GitLab.
     pub user: String,
     pub name: String,
     pub sub_dir: String,
     pub _ref: String,
     pub ssh: String,
     pub mode: ValidModes,
-    refs: Vec<Ref>
+    /// Kind of server the domain runs, when known.
+    pub server: Option<GitServer>,
+    /// Where `url` is actually fetched from after applying the configured
+    /// rewrite rules, in order. Empty when `url` is used as is.
+    pub mirrors: Vec<String>,
+    refs: Vec<Ref>,
+    /// Set while a GitLab path hasn't been split into project and
+    /// subdirectory yet, see `resolve`.
+    unresolved: bool,
 }
 impl Repository {
-    pub fn parse(src: &str) -> Self {
+    /// Parses `src`, using `config` for the default host and mirror rules.
+    pub fn parse(src: &str, config: &Config) -> Self {
         log!(format!("Parsing repository: '{}'...", src));
+        if let Some(repo) = Self::parse_archive(src, config) {
+            success!(format!("Using archive '{}' as source", repo.url));
+            return repo;
+        }
+        if let Some(repo) = Self::parse_local(src) {
+            success!(format!("Using local '{}' as source", repo.url));
+            return repo;
+        }
+        if let Some(repo) = Self::parse_web_url(src, config) {
+            success!(format!("Using '{}' at '{}' as repository source", repo.url, repo._ref));
+            return repo;
+        }
         let re = Regex::new(RE_VALID_REPO).unwrap();
         let matches = re.captures(src).unwrap();
 
-        let mut domain = String::from("github.com");
+        let mut domain = config.host();
         for i in 1..=3 {
-            if let Some(m) = matches.get(i) { domain = m.as_str().into(); }
+            if let Some(m) = matches.get(i) { domain = config::normalize_host(m.as_str()); }
         }
 
+        let server = config.server(&domain);
         let mut mode = ValidModes::Tar;
-        if ! SUPPORTED_DOMAINS.contains(&domain.as_str()) {
+        if server.is_none() {
             mode = ValidModes::Git;
             warn!("ReGit only supports GitHub, GitLab, SourceHut and BitBucket domains.");
             warn!("Switching to 'Git' mode. It might not work properly and will be slower.");
@@ -63,15 +98,21 @@ impl Repository {
 
         let user = matches.get(4).map_or("", |m| m.as_str()).to_string();
         let name = matches.get(5).map_or("", |m| m.as_str()).to_string();
-        let sub_dir = matches.get(6).map_or("", |m| m.as_str()).to_string();
+        let sub_dir = matches.get(6).map_or("", |m| m.as_str().trim_start_matches('/')).to_string();
+        // Where the project ends is only known once GitLab is asked.
+        let unresolved = server == Some(GitServer::GitLab) && !sub_dir.is_empty();
         let _ref = matches.get(7).map_or("HEAD", |m| m.as_str()).to_string();
 
         let ssh = format!("git@{domain}:{user}/{name}");
         let url = format!("https://{domain}/{user}/{name}");
 
-        let refs = Self::fetch_refs(url.as_str());
+        let mirrors = config.mirrors_for(&url);
+        let refs = match unresolved {
+            true => vec![],
+            false => Self::fetch_refs(&mirrors, config),
+        };
 
-        success!(format!("Using '{}' as repository source", url));
+        if !unresolved { success!(format!("Using '{}' as repository source", url)); }
 
         Repository { 
             url, 
@@ -82,109 +123,377 @@ impl Repository {
             _ref, 
             ssh, 
             mode, 
+            server,
+            mirrors,
             refs,
+            unresolved,
         }
     }
 
+    /// Parses URLs copied from a host's web UI, e.g.
+    /// `https://github.com/org/repo/tree/main/packages/ui` or
+    /// `https://gitlab.com/group/sub/repo/-/tree/v2/templates`, into the
+    /// repository, ref and subdirectory they point at.
+    pub fn parse_web_url(src: &str, config: &Config) -> Option<Self> {
+        let parsed = url::Url::parse(src).ok()?;
+        if !["http", "https"].contains(&parsed.scheme()) { return None }
+        let domain = parsed.host_str()?.to_owned();
+        let server = config.server(&domain);
+        let segments: Vec<&str> = parsed.path_segments()?.filter(|s| !s.is_empty()).collect();
+
+        let markers: &[&str] = match server {
+            Some(GitServer::BitBucket) => &["src", "commits"],
+            _ => &["tree", "blob", "commit"],
+        };
+        let (project, kind, rest) = match segments.iter().position(|s| *s == "-") {
+            // GitLab separates the project path from the page with `/-/`.
+            Some(i) => (&segments[..i], *segments.get(i + 1)?, &segments[(i + 2).min(segments.len())..]),
+            None => {
+                let i = segments.iter().skip(2).position(|s| markers.contains(s))? + 2;
+                (&segments[..i], segments[i], &segments[i + 1..])
+            },
+        };
+        if project.len() < 2 || rest.is_empty() { return None }
+
+        let user = project[..project.len() - 1].join("/");
+        let name = project[project.len() - 1].trim_end_matches(".git").to_owned();
+        let url = format!("https://{}/{}/{}", domain, user, name);
+        let mirrors = config.mirrors_for(&url);
+        let refs = Self::fetch_refs(&mirrors, config);
+
+        let (_ref, mut sub_dir) = match kind {
+            "commit" | "commits" => (rest[0].to_owned(), String::new()),
+            // SourceHut puts `item` between the ref and the path.
+            _ if server == Some(GitServer::SourceHut) => match rest.iter().position(|s| *s == "item") {
+                Some(i) => (rest[..i].join("/"), rest[i + 1..].join("/")),
+                None => (rest.join("/"), String::new()),
+            },
+            _ => Self::split_ref_path(rest, &refs),
+        };
+        if kind == "blob" {
+            sub_dir = sub_dir.rsplit_once('/').map_or(String::new(), |(dir, _)| dir.to_owned());
+        }
+
+        let mode = match server {
+            Some(_) => ValidModes::Tar,
+            None => ValidModes::Git,
+        };
+        Some(Repository {
+            ssh: format!("git@{}:{}/{}", domain, user, name),
+            url,
+            domain,
+            user,
+            name,
+            sub_dir,
+            _ref,
+            mode,
+            server,
+            mirrors,
+            refs,
+            ..Default::default()
+        })
+    }
+
+    /// Parses plain archive sources: `https://…/x.tar.gz`, `file:///path/x.zip`
+    /// or local archive paths, each with an optional `//sub/dir` selector.
+    pub fn parse_archive(src: &str, config: &Config) -> Option<Self> {
+        let scheme_end = src.find("://").map_or(0, |i| i + 3);
+        let (location, sub_dir) = match src[scheme_end..].find("//") {
+            Some(i) => (&src[..scheme_end + i], &src[scheme_end + i + 2..]),
+            None => (src, ""),
+        };
+
+        let (url, domain) = if location.starts_with("https://") || location.starts_with("http://") {
+            let url = url::Url::parse(location).ok()?;
+            if !has_archive_ext(url.path()) { return None }
+            (location.to_owned(), url.host_str()?.to_owned())
+        } else {
+            let path = location.strip_prefix("file://").unwrap_or(location);
+            if !has_archive_ext(path) && !Path::new(path).is_file() { return None }
+            (path.to_owned(), String::from("local"))
+        };
+
+        let file_name = url.rsplit('/').find(|s| !s.is_empty()).unwrap_or(&url);
+        let file_name = file_name.split(['?', '#']).next().unwrap_or(file_name);
+        let name = ARCHIVE_EXTENSIONS.iter()
+            .find_map(|ext| file_name.strip_suffix(ext))
+            .unwrap_or(file_name)
+            .to_owned();
+
+        let mirrors = match domain.as_str() {
+            "local" => vec![],
+            _ => config.mirrors_for(&url),
+        };
+        Some(Repository {
+            url,
+            domain,
+            name,
+            sub_dir: sub_dir.trim_matches('/').to_owned(),
+            mode: ValidModes::Archive,
+            mirrors,
+            ..Default::default()
+        })
+    }
+
+    /// Parses local sources like `./templates/api`, `/abs/path/repo#branch` or
+    /// `file:///repo.git/sub#tag`. With a ref, or for bare repositories, the
+    /// enclosing git repository is exported at that ref. Otherwise the
+    /// directory is copied as it is on disk.
+    pub fn parse_local(src: &str) -> Option<Self> {
+        let path = src.strip_prefix("file://");
+        let is_path = path.is_some() || ["/", "./", "../", "~/"].iter().any(|p| src.starts_with(p)) || src == ".";
+        if !is_path { return None }
+
+        let (path, _ref) = match path.unwrap_or(src).split_once('#') {
+            Some((path, _ref)) => (path, Some(_ref)),
+            None => (path.unwrap_or(src), None),
+        };
+        let path = match path.strip_prefix("~/") {
+            Some(rest) => dirs::home_dir()?.join(rest),
+            None => PathBuf::from(path),
+        };
+        let path = std::path::absolute(path).ok()?;
+
+        let is_bare = |dir: &Path| dir.join("HEAD").is_file() && dir.join("objects").is_dir();
+        let git_root = path.ancestors().find(|dir| dir.join(".git").exists() || is_bare(dir));
+        let (url, sub_dir, _ref) = match git_root {
+            Some(root) if _ref.is_some() || is_bare(root) => (
+                root.to_owned(),
+                path.strip_prefix(root).unwrap().to_string_lossy().into_owned(),
+                _ref.unwrap_or("HEAD").to_owned(),
+            ),
+            _ => (path.clone(), String::new(), String::new()),
+        };
+        let name = url.file_name().map_or(String::from("local"), |n| n.to_string_lossy().trim_end_matches(".git").to_owned());
+
+        Some(Repository {
+            url: url.to_string_lossy().into(),
+            domain: String::from("local"),
+            name,
+            sub_dir,
+            _ref,
+            mode: ValidModes::Local,
+            ..Default::default()
+        })
+    }
+
+    /// Whether an archive source lives on disk instead of behind a URL.
+    pub fn is_local(&self) -> bool {
+        self.domain == "local"
+    }
+
+    /// GitLab namespaces can nest subgroups to any depth, so a path like
+    /// `group/sub/project/dir` is ambiguous until the API is asked which
+    /// prefix is a project. Does that when needed, then lists the refs of the
+    /// project found.
+    pub fn resolve(&mut self, config: &Config) -> Result<(), String> {
+        if !self.unresolved { return Ok(()) }
+        let path = format!("{}/{}/{}", self.user, self.name, self.sub_dir);
+        let api = format!("https://{}/api/v4", self.domain);
+        let (user, name, sub_dir) = Self::split_gitlab_path(&api, &path, config)?;
+
+        self.url = format!("https://{}/{}/{}", self.domain, user, name);
+        self.ssh = format!("git@{}:{}/{}", self.domain, user, name);
+        (self.user, self.name, self.sub_dir) = (user, name, sub_dir);
+        self.mirrors = config.mirrors_for(&self.url);
+        self.refs = Self::fetch_refs(&self.mirrors, config);
+        self.unresolved = false;
+        success!(format!("Using '{}' as repository source", self.url));
+        Ok(())
+    }
+
+    /// Asks the API at `api` which prefix of `path` is a project, shortest
+    /// first, and returns it split into namespace, name and subdirectory.
+    fn split_gitlab_path(api: &str, path: &str, config: &Config) -> Result<(String, String, String), String> {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let proxy = config.proxy.as_deref().unwrap_or_default();
+        let token = token_for(&config.auth, api);
+
+        log!(format!("Resolving GitLab project in '{}'...", path));
+        for i in 2..=segments.len() {
+            let project = segments[..i].join("/");
+            let url = format!("{}/projects/{}", api, url::form_urlencoded::byte_serialize(project.as_bytes()).collect::<String>());
+            let res = util::get(&url, proxy, token)
+                .map_err(|e| format!("couldn't reach the GitLab API at '{}': {}", api, e))?;
+            match res.status_code {
+                200 => return Ok((segments[..i - 1].join("/"), segments[i - 1].to_owned(), segments[i..].join("/"))),
+                404 => continue,
+                code => return Err(format!("GitLab API answered {} for '{}'", code, project)),
+            }
+        }
+        Err(format!("no GitLab project found in '{}'", path))
+    }
+
     pub fn archive_url(&self, hash: &str) -> String {
-        match self.domain.as_str() {
-            "gitlab" => format!("{}/repository/archive.tar.gz?ref={}", self.url, hash),
-            "bitbucket" => format!("{}/get/{}.tar.gz", self.url, hash),
-            _ => format!("{}/archive/{}.tar.gz", self.url, hash),
+        self.archive_url_at(&self.url, hash)
+    }
+
+    /// Archive URLs on every mirror, in the order they should be tried.
+    pub fn archive_urls(&self, hash: &str) -> Vec<String> {
+        self.urls().iter().map(|url| self.archive_url_at(url, hash)).collect()
+    }
+
+    fn archive_url_at(&self, url: &str, hash: &str) -> String {
+        match self.server {
+            Some(GitServer::GitLab) => format!("{}/-/archive/{}/{}-{}.tar.gz", url, hash, self.name, hash),
+            Some(GitServer::BitBucket) => format!("{}/get/{}.tar.gz", url, hash),
+            _ => format!("{}/archive/{}.tar.gz", url, hash),
+        }
+    }
+
+    /// URLs to fetch from, in order: the configured mirrors, or `url` itself.
+    pub fn urls(&self) -> Vec<String> {
+        match self.mirrors.is_empty() {
+            true => vec![self.url.to_owned()],
+            false => self.mirrors.to_owned(),
         }
     }
 
     pub fn full_name(&self) -> String {
-        format!("{}/{}{}", self.user, self.name, self.sub_dir)
+        match self.sub_dir.as_str() {
+            "" => format!("{}/{}", self.user, self.name),
+            sub_dir => format!("{}/{}/{}", self.user, self.name, sub_dir),
+        }
     }
 
     pub fn full_name_ref(&self) -> String {
         format!("{}:{}", self.full_name(), self._ref)
     }
 
-    pub fn get_hash(&self) -> String {
+    pub fn get_hash(&self) -> Result<String, String> {
+        if is_commit_hash(&self._ref) {
+            return Ok(self._ref.to_owned());
+        }
+        if self.refs.is_empty() {
+            return Err(format!("couldn't list the refs of '{}'", self.url));
+        }
         if self._ref == "HEAD" {
             return self.refs.iter().find(|_ref| _ref.kind == "HEAD")
-                .expect("should find hash of HEAD ref")
-                .hash.to_owned()
+                .map(|_ref| _ref.hash.to_owned())
+                .ok_or_else(|| format!("'{}' has no HEAD", self.url))
+        }
+        self._select_ref(self._ref.as_str()).map(|_ref| _ref.hash)
+    }
+
+    /// Location of this repository's files relative to the cache directory.
+    pub fn cache_path(&self) -> String {
+        match self.mode {
+            ValidModes::Archive => format!("{}/_archives/{}", self.domain, &sha256_hex(self.url.as_bytes())[..16]),
+            ValidModes::Local => format!("local/_repos/{}", &sha256_hex(self.url.as_bytes())[..16]),
+            _ => format!("{}/{}/{}", self.domain, self.user, self.name),
         }
-        self._select_ref(self._ref.as_str()).hash
     }
 
-    pub(crate) fn get_hash_cached(&self, cache: &Cache) -> String {
-        if let Some(hash) = cache.get_cached_hash(&self.full_name(), &self._ref) {
-            return hash;
+    /// Resolves the ref from the fetched refs, falling back to the hash it had
+    /// last time when they couldn't be fetched, e.g. offline.
+    pub(crate) fn get_hash_cached(&self, cache: &Cache) -> Result<String, String> {
+        let repo_dir = format!("{}/{}", cache.dir(), self.cache_path());
+        let cached = cache.get_cached_hash(&repo_dir, &self._ref);
+        if let Some(hash) = cached.filter(|_| self.refs.is_empty()) {
+            return Ok(hash);
         }
         self.get_hash()
     }
 }
 impl Repository {
-    fn fetch_refs(url: &str) -> Vec<Ref> {
+    fn fetch_refs(urls: &[String], config: &Config) -> Vec<Ref> {
         log!("Fetching refs...");
-        let output = cmd!("git", ["ls-remote", url]).stdout;
-        let stdout = str::from_utf8(&output).unwrap();
+        let proxy = config.proxy.as_deref().unwrap_or_default();
+        for url in urls {
+            let output = git_remote(url, proxy, &config.auth).args(["ls-remote", url]).output();
+            let Some(output) = output.ok().filter(|o| o.status.success()) else {
+                warn!(format!("Couldn't list refs of '{}'", url));
+                continue;
+            };
+            let refs = Self::parse_refs(&String::from_utf8_lossy(&output.stdout));
+            log!(format!("Fetched {} refs", refs.len()));
+            return refs;
+        }
+        vec![]
+    }
 
-        let mut refs = vec![];
-        for row in stdout.split("\n").filter(|x| !x.is_empty()) {
-            let cols = row.split("\t").collect::<Vec<&str>>();
-            let hash = *cols.get(0).unwrap();
-            let _ref = *cols.get(1).unwrap();
+    /// Commit `name` points to in `git ls-remote` output: `HEAD`, or the
+    /// branch or tag called exactly that, tags peeled to their commit.
+    pub(crate) fn find_ref(stdout: &str, name: &str) -> Option<String> {
+        let refs = Self::parse_refs(stdout);
+        match name {
+            "HEAD" => refs.iter().find(|r| r.kind == "HEAD"),
+            name => refs.iter().find(|r| matches!(r.kind.as_str(), "branch" | "tag") && r.name == name),
+        }.map(|r| r.hash.to_owned())
+    }
 
-            if _ref == "HEAD" {
-                return vec![Ref::new("HEAD", "", hash)];
+    /// Parses `git ls-remote` output. Annotated tags take the hash of the
+    /// commit they point to.
+    fn parse_refs(stdout: &str) -> Vec<Ref> {
+        let mut refs: Vec<Ref> = vec![];
+        for row in stdout.lines() {
+            let Some((hash, full_name)) = row.split_once('\t') else { continue };
+            if full_name == "HEAD" {
+                refs.push(Ref::new("HEAD", "", hash));
+                continue;
+            }
+            let Some((kind, name)) = full_name.strip_prefix("refs/").and_then(|r| r.split_once('/')) else { continue };
+            let kind = match kind { "heads" => "branch", "tags" => "tag", kind => kind };
+
+            if let Some(name) = name.strip_suffix("^{}") {
+                if let Some(tag) = refs.iter_mut().find(|r| r.kind == kind && r.name == name) {
+                    tag.hash = hash.into();
+                }
+                continue;
             }
-
-            let cmd_refs = String::from_utf8(cmd!(_ref).stdout).unwrap();
-            let matches = regex::Regex::new(r"/refs/\w+/(.+)/").unwrap().captures(&cmd_refs);
-            let (kind, name) = match matches {
-                Some(ms) => {
-                    if ms.len() < 3 { continue; }
-                    (ms.get(1).unwrap().as_str(), ms.get(2).unwrap().as_str())
-                },
-                None => continue
-            };
-
             refs.push(Ref::new(kind, name, hash));
         }
-
-        println!("\nFetched refs: {:#?}\n", refs);
         refs
     }
 
-    fn _select_ref(&self, selector: &str) -> Ref {
-        println!("Selecting ref with selector '{}'...", selector);
+    /// Splits web URL segments like `feature/x/packages/ui` into a ref and a
+    /// path, preferring the longest prefix that names a remote ref. Falls back
+    /// to the first segment when no ref matches.
+    fn split_ref_path(segments: &[&str], refs: &[Ref]) -> (String, String) {
+        let split = (1..=segments.len()).rev()
+            .find(|&i| {
+                let name = segments[..i].join("/");
+                refs.iter().any(|r| r.kind != "HEAD" && r.name == name)
+            })
+            .unwrap_or(1.min(segments.len()));
+        (segments[..split].join("/"), segments[split..].join("/"))
+    }
+
+    fn _select_ref(&self, selector: &str) -> Result<Ref, String> {
+        log!(format!("Selecting ref with selector '{}'...", selector));
         for r in &self.refs {
             if r.name == selector {
-                println!("Found ref exact match '{}'", r.name);
-                return r.to_owned();
+                log!(format!("Found ref exact match '{}'", r.name));
+                return Ok(r.to_owned());
             }
         }
 
-        assert!(selector.len() >= 8, "selector must be at least 8 char long");
-        for r in &self.refs {
-            if r.hash.starts_with(selector) {
-                println!("Found ref partial match '{}'", r.name);
-                return r.to_owned();
+        if selector.len() >= 8 {
+            for r in &self.refs {
+                if r.hash.starts_with(selector) {
+                    log!(format!("Found ref partial match '{}'", r.name));
+                    return Ok(r.to_owned());
+                }
             }
         }
-        panic!("FATAL: ref with selector '{}' not found in current repository", selector);
+        Err(format!("ref '{}' not found in '{}'", selector, self.url))
     }
 }
 
-
-
-
+impl Repository {
+    pub fn url(&self) -> String { self.url.to_owned() }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    const TEST_SRC: &'static str = "solidjs/templates/ts";
+    const TEST_SRC: &str = "solidjs/templates/ts";
 
     #[test]
     fn parses_repository() {
         println!("Testing...");
-        let repo = Repository::parse(TEST_SRC);
+        let repo = Repository::parse(TEST_SRC, &Config::default());
 
         assert_eq!(repo.url, "https://github.com/solidjs/templates");
         assert_eq!(repo.domain, "github.com");
@@ -196,14 +505,177 @@ mod tests {
         assert_eq!(repo._ref, "HEAD");
     }
 
+    #[test]
+    fn parses_with_given_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::parse(&format!(r#"
+            host = "sourcehut"
+            [mirrors]
+            "git.sr.ht/" = "file://{}/"
+        "#, tmp.path().to_str().unwrap())).unwrap();
+        let repo = Repository::parse("~user/tpl", &config);
+        assert_eq!(repo.url, "https://git.sr.ht/~user/tpl");
+        assert_eq!(repo.urls(), vec![format!("file://{}/~user/tpl", tmp.path().to_str().unwrap())]);
+    }
+
     #[test]
     fn fetches_refs() {
-        let repo = Repository::parse(TEST_SRC);
+        let repo = Repository::parse(TEST_SRC, &Config::default());
 
         assert!(!repo.refs.is_empty(), "refs are empty: {:#?}", repo.refs);
     }
-}
 
-impl Repository {
-    pub fn url(&self) -> String { self.url.to_owned() }
-}
\ No newline at end of file
+    #[test]
+    fn parses_archive_sources() {
+        let repo = Repository::parse("https://example.com/releases/tpl-1.0.tar.gz//templates/api/", &Config::default());
+        assert_eq!(repo.mode, ValidModes::Archive);
+        assert_eq!(repo.url, "https://example.com/releases/tpl-1.0.tar.gz");
+        assert_eq!(repo.domain, "example.com");
+        assert_eq!(repo.name, "tpl-1.0");
+        assert_eq!(repo.sub_dir, "templates/api");
+        assert!(!repo.is_local());
+
+        let repo = Repository::parse("file:///tmp/x.zip", &Config::default());
+        assert_eq!((repo.url.as_str(), repo.sub_dir.as_str()), ("/tmp/x.zip", ""));
+        assert!(repo.is_local());
+
+        let repo = Repository::parse("./vendor/tpl.tgz//sub", &Config::default());
+        assert_eq!((repo.url.as_str(), repo.name.as_str(), repo.sub_dir.as_str()), ("./vendor/tpl.tgz", "tpl", "sub"));
+
+        assert!(Repository::parse_archive("https://github.com/user/repo", &Config::default()).is_none());
+        assert!(Repository::parse_archive("user/repo/sub", &Config::default()).is_none());
+    }
+
+    #[test]
+    fn parses_local_sources() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("mono");
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("templates/api")).unwrap();
+        let root_str = root.to_str().unwrap();
+
+        let repo = Repository::parse(&format!("{}/templates/api", root_str), &Config::default());
+        assert_eq!(repo.mode, ValidModes::Local);
+        assert_eq!(repo.url, format!("{}/templates/api", root_str), "without a ref the directory is copied");
+        assert_eq!((repo.sub_dir.as_str(), repo._ref.as_str()), ("", ""));
+
+        let repo = Repository::parse(&format!("file://{}/templates/api#v2", root_str), &Config::default());
+        assert_eq!(repo.url, root_str, "with a ref the repository is exported");
+        assert_eq!((repo.sub_dir.as_str(), repo._ref.as_str(), repo.name.as_str()), ("templates/api", "v2", "mono"));
+
+        let bare = tmp.path().join("tpl.git");
+        std::fs::create_dir_all(bare.join("objects")).unwrap();
+        std::fs::write(bare.join("HEAD"), "ref: refs/heads/main").unwrap();
+        let repo = Repository::parse(&format!("file://{}/sub", bare.to_str().unwrap()), &Config::default());
+        assert_eq!((repo.sub_dir.as_str(), repo._ref.as_str(), repo.name.as_str()), ("sub", "HEAD", "tpl"));
+
+        assert!(Repository::parse_local("user/repo").is_none());
+    }
+
+    #[test]
+    fn parses_ls_remote_output() {
+        let refs = Repository::parse_refs(
+            "aaa\tHEAD\nbbb\trefs/heads/feature/x\nccc\trefs/tags/v1\nddd\trefs/tags/v1^{}\neee\trefs/pull/1/head\n"
+        );
+        let found: Vec<_> = refs.iter().map(|r| (r.kind.as_str(), r.name.as_str(), r.hash.as_str())).collect();
+        assert_eq!(found, vec![
+            ("HEAD", "", "aaa"),
+            ("branch", "feature/x", "bbb"),
+            ("tag", "v1", "ddd"),
+            ("pull", "1/head", "eee"),
+        ]);
+    }
+
+    #[test]
+    fn resolves_refs_with_slashes() {
+        let refs = vec![Ref::new("branch", "feature", "a"), Ref::new("branch", "feature/x", "b")];
+        let split = |path: &str| Repository::split_ref_path(&path.split('/').collect::<Vec<_>>(), &refs);
+        assert_eq!(split("feature/x/packages/ui"), ("feature/x".into(), "packages/ui".into()));
+        assert_eq!(split("feature/packages"), ("feature".into(), "packages".into()));
+        assert_eq!(split("main/packages/ui"), ("main".into(), "packages/ui".into()));
+    }
+
+    #[test]
+    fn reports_refs_it_cant_resolve() {
+        let hash = "0123456789abcdef0123456789abcdef01234567";
+        let mut repo = Repository { url: "https://github.com/user/repo".into(), _ref: "HEAD".into(), ..Default::default() };
+        assert!(repo.get_hash().is_err(), "no refs could be listed");
+
+        repo.refs = vec![Ref::new("HEAD", "", hash), Ref::new("branch", "main", hash)];
+        assert_eq!(repo.get_hash(), Ok(hash.to_owned()));
+        repo._ref = "0123456".into();
+        assert!(repo.get_hash().is_err(), "too short to match a hash");
+        repo._ref = "missing".into();
+        assert!(repo.get_hash().unwrap_err().contains("'missing' not found"));
+    }
+
+    #[test]
+    fn parses_web_urls() {
+        let parse = |src: &str| {
+            let repo = Repository::parse_web_url(src, &Config::default()).unwrap();
+            (repo.user, repo.name, repo._ref, repo.sub_dir)
+        };
+        let expect = |user: &str, name: &str, _ref: &str, sub_dir: &str| {
+            (user.to_owned(), name.to_owned(), _ref.to_owned(), sub_dir.to_owned())
+        };
+
+        assert_eq!(parse("https://github.com/org/repo/tree/main/packages/ui"), expect("org", "repo", "main", "packages/ui"));
+        assert_eq!(parse("https://github.com/org/repo/blob/main/packages/ui/index.ts"), expect("org", "repo", "main", "packages/ui"));
+        assert_eq!(parse("https://github.com/org/repo/commit/0123456789abcdef0123456789abcdef01234567"),
+            expect("org", "repo", "0123456789abcdef0123456789abcdef01234567", ""));
+        assert_eq!(parse("https://gitlab.com/group/sub/repo/-/tree/v2/templates"), expect("group/sub", "repo", "v2", "templates"));
+        assert_eq!(parse("https://bitbucket.org/ws/repo/src/main/app/"), expect("ws", "repo", "main", "app"));
+        assert_eq!(parse("https://git.sr.ht/~user/repo/tree/release/1.x/item/docs"), expect("~user", "repo", "release/1.x", "docs"));
+
+        assert!(Repository::parse_web_url("https://github.com/org/repo", &Config::default()).is_none());
+        assert!(Repository::parse_web_url("org/repo/tree/main", &Config::default()).is_none());
+    }
+
+    #[test]
+    fn resolves_gitlab_subgroups_through_api() {
+        let base = crate::util::serve(vec![
+            ("/api/v4/projects/group%2Fsubgroup%2Fproject".into(), 200, b"{}".to_vec()),
+        ]);
+        let api = format!("{}/api/v4", base);
+
+        let config = Config::default();
+
+        assert_eq!(
+            Repository::split_gitlab_path(&api, "group/subgroup/project/templates/api", &config),
+            Ok(("group/subgroup".into(), "project".into(), "templates/api".into())),
+        );
+        assert!(
+            Repository::split_gitlab_path(&api, "group/other/dir", &config).is_err(),
+            "should report that no prefix is a project instead of guessing",
+        );
+    }
+
+    #[test]
+    fn defers_gitlab_lookup_to_resolve() {
+        let config = Config::parse(r#"
+            [servers]
+            "git.corp" = "gitlab"
+        "#).unwrap();
+        let repo = Repository::parse("git.corp/group/sub/project/templates", &config);
+
+        assert_eq!(repo.server, Some(GitServer::GitLab));
+        assert_eq!(repo.mode, ValidModes::Tar);
+        assert!(repo.unresolved, "parsing shouldn't ask the API");
+        assert!(repo.refs.is_empty());
+    }
+
+    #[test]
+    fn builds_gitlab_archive_url_with_namespace() {
+        let repo = Repository {
+            url: "https://gitlab.com/group/subgroup/project".into(),
+            domain: "gitlab.com".into(),
+            server: Some(GitServer::GitLab),
+            user: "group/subgroup".into(),
+            name: "project".into(),
+            ..Default::default()
+        };
+
+        assert_eq!(repo.archive_url("abc"), "https://gitlab.com/group/subgroup/project/-/archive/abc/project-abc.tar.gz");
+        assert_eq!(repo.cache_path(), "gitlab.com/group/subgroup/project");
+    }
+}
@@ -0,0 +1,235 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+use super::*;
+use crate::archive;
+
+/// Manifest a template may keep at its root to declare its variables. It's
+/// read before extraction and never extracted itself.
+pub const TEMPLATE_FILE: &str = "regit.template.toml";
+
+const RE_BRACES: &str = r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}";
+const RE_UNDERSCORES: &str = r"__([A-Za-z][A-Za-z0-9_]*?)__";
+
+/// Asks for the value of a declared variable.
+pub type Ask<'a> = dyn FnMut(&str, &Variable) -> String + 'a;
+
+/// Variables given on the command line or in a vars file. Templating is off
+/// unless some are given or it's asked for explicitly.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TemplateVars {
+    pub vars: BTreeMap<String, String>,
+    /// Ask for declared variables that weren't given.
+    pub prompt: bool,
+}
+
+/// Variables declared in `regit.template.toml`:
+///
+/// ```toml
+/// [variables.project_name]
+/// description = "Name of the project"
+/// default = "my-app"
+/// pattern = "^[a-z][a-z0-9-]*$"
+///
+/// [variables.license]
+/// choices = ["MIT", "Apache-2.0"]
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateManifest {
+    #[serde(default)]
+    pub variables: BTreeMap<String, Variable>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variable {
+    pub description: Option<String>,
+    pub default: Option<String>,
+    /// Regex the value must match.
+    pub pattern: Option<String>,
+    /// Values the variable is limited to.
+    #[serde(default)]
+    pub choices: Vec<String>,
+}
+impl Variable {
+    fn validate(&self, name: &str, value: &str) -> Result<(), String> {
+        if !self.choices.is_empty() && !self.choices.iter().any(|c| c == value) {
+            return Err(format!("'{}' must be one of {}, got '{}'", name, self.choices.join(", "), value));
+        }
+        if let Some(pattern) = &self.pattern {
+            let re = Regex::new(pattern).map_err(|e| format!("invalid pattern for '{}': {}", name, e))?;
+            if !re.is_match(value) {
+                return Err(format!("'{}' must match '{}', got '{}'", name, pattern, value));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TemplateManifest {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|e| format!("invalid '{}': {}", TEMPLATE_FILE, e))
+    }
+
+    /// Reads the manifest at `prefix` inside `file`, if the template has one.
+    pub fn read(file: &Path, prefix: &str) -> Result<Option<Self>, String> {
+        let path = format!("{}{}", prefix, TEMPLATE_FILE);
+        let mut contents = None;
+        archive::open(file)?.for_each_entry(&mut |entry, reader| {
+            if entry.path == path {
+                let mut text = String::new();
+                reader.read_to_string(&mut text).map_err(|e| e.to_string())?;
+                contents = Some(text);
+            }
+            Ok(())
+        })?;
+        contents.map(|text| Self::parse(&text)).transpose()
+    }
+
+    /// Settles the value of every declared variable: given values are
+    /// validated, missing ones are asked for through `ask` when there is one,
+    /// and fall back to their default otherwise. Given variables the manifest
+    /// doesn't declare are kept as they are.
+    pub fn resolve(
+        &self,
+        given: &BTreeMap<String, String>,
+        mut ask: Option<&mut Ask>,
+    ) -> Result<BTreeMap<String, String>, String> {
+        let mut vars = given.clone();
+        for (name, variable) in &self.variables {
+            let value = match (given.get(name), ask.as_mut()) {
+                (Some(value), _) => value.to_owned(),
+                (None, Some(ask)) => loop {
+                    let value = ask(name, variable);
+                    let value = if value.is_empty() { variable.default.clone().unwrap_or_default() } else { value };
+                    match variable.validate(name, &value) {
+                        Ok(()) if !value.is_empty() => break value,
+                        Ok(()) => { warn!(format!("'{}' is required", name)); },
+                        Err(e) => { warn!(e); },
+                    }
+                },
+                (None, None) => variable.default.clone().ok_or_else(|| format!("missing value for '{}', pass --var {}=...", name, name))?,
+            };
+            variable.validate(name, &value)?;
+            vars.insert(name.to_owned(), value);
+        }
+        Ok(vars)
+    }
+}
+
+/// Renders `{{name}}` and `__name__` placeholders. Placeholders of unknown
+/// variables are left as they are.
+#[derive(Debug, Clone)]
+pub struct Template {
+    vars: BTreeMap<String, String>,
+    braces: Regex,
+    underscores: Regex,
+}
+impl Template {
+    pub fn new(vars: BTreeMap<String, String>) -> Self {
+        Self {
+            vars,
+            braces: Regex::new(RE_BRACES).unwrap(),
+            underscores: Regex::new(RE_UNDERSCORES).unwrap(),
+        }
+    }
+
+    pub fn vars(&self) -> &BTreeMap<String, String> {
+        &self.vars
+    }
+
+    pub fn render(&self, text: &str) -> String {
+        let replace = |caps: &Captures| match self.vars.get(&caps[1]) {
+            Some(value) => value.to_owned(),
+            None => caps[0].to_owned(),
+        };
+        let text = self.braces.replace_all(text, replace);
+        self.underscores.replace_all(&text, replace).into_owned()
+    }
+
+    /// Renders file contents, leaving binary files alone.
+    pub fn render_bytes(&self, bytes: Vec<u8>) -> Vec<u8> {
+        if bytes.iter().take(8000).any(|b| *b == 0) { return bytes }
+        match String::from_utf8(bytes) {
+            Ok(text) => self.render(&text).into_bytes(),
+            Err(e) => e.into_bytes(),
+        }
+    }
+}
+
+/// Reads `key = "value"` pairs from a TOML vars file.
+pub fn read_vars_file(path: &Path) -> Result<BTreeMap<String, String>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("can't read '{}': {}", path.display(), e))?;
+    let table: toml::Table = toml::from_str(&contents).map_err(|e| format!("invalid '{}': {}", path.display(), e))?;
+    Ok(table.into_iter()
+        .map(|(key, value)| match value {
+            toml::Value::String(s) => (key, s),
+            value => (key, value.to_string()),
+        })
+        .collect())
+}
+
+/// Parses `key=value` from `--var`.
+pub fn parse_var(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_owned(), value.to_owned())),
+        _ => Err(format!("'{}' isn't a key=value pair", var)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let template = Template::new(vars(&[("project_name", "demo"), ("crate_name", "demo_core")]));
+        assert_eq!(template.render("name = \"{{project_name}}\" # {{ project_name }}"), "name = \"demo\" # demo");
+        assert_eq!(template.render("src/__crate_name__/lib.rs"), "src/demo_core/lib.rs");
+        assert_eq!(template.render("{{unknown}} __init__.py"), "{{unknown}} __init__.py", "unknown names stay");
+        assert_eq!(template.render_bytes(b"\0{{project_name}}".to_vec()), b"\0{{project_name}}", "binary files stay");
+        assert_eq!(template.render_bytes(vec![0xff, b'{']), vec![0xff, b'{']);
+    }
+
+    #[test]
+    fn resolves_declared_variables() {
+        let manifest = TemplateManifest::parse(r#"
+            [variables.project_name]
+            pattern = "^[a-z][a-z0-9-]*$"
+            [variables.license]
+            default = "MIT"
+            choices = ["MIT", "Apache-2.0"]
+        "#).unwrap();
+
+        let resolved = manifest.resolve(&vars(&[("project_name", "demo"), ("extra", "x")]), None).unwrap();
+        assert_eq!(resolved, vars(&[("extra", "x"), ("license", "MIT"), ("project_name", "demo")]));
+
+        assert!(manifest.resolve(&vars(&[]), None).is_err(), "variables without default are required");
+        assert!(manifest.resolve(&vars(&[("project_name", "Demo")]), None).is_err(), "should check the pattern");
+        assert!(manifest.resolve(&vars(&[("project_name", "demo"), ("license", "GPL")]), None).is_err());
+
+        let mut answers = vec!["", "Bad Name", "demo"].into_iter();
+        let mut ask = |_: &str, _: &Variable| answers.next().unwrap().to_owned();
+        let resolved = manifest.resolve(&vars(&[]), Some(&mut ask)).unwrap();
+        assert_eq!(resolved, vars(&[("license", "MIT"), ("project_name", "demo")]), "should ask until valid");
+    }
+
+    #[test]
+    fn parses_vars() {
+        assert_eq!(parse_var("name=a=b").unwrap(), ("name".into(), "a=b".into()));
+        assert!(parse_var("name").is_err());
+
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("vars.toml");
+        fs::write(&file, "project_name = \"demo\"\nport = 8080\n").unwrap();
+        assert_eq!(read_vars_file(&file).unwrap(), vars(&[("port", "8080"), ("project_name", "demo")]));
+    }
+}
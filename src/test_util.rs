@@ -0,0 +1,28 @@
+use std::{fs, path::Path, process::Command};
+
+use crate::util::mkdirp;
+
+/// Runs git in `dir` as a fixed committer, so tests don't depend on the
+/// user's identity, and returns its trimmed output.
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .args(["-c", "user.name=regit", "-c", "user.email=regit@localhost"])
+        .arg("-C").arg(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).trim().to_owned()
+}
+
+/// Writes `files` into the repository at `repo` and commits them all,
+/// returning the new commit.
+pub fn commit(repo: &Path, files: &[(&str, &str)]) -> String {
+    for (path, contents) in files {
+        mkdirp(repo.join(path).parent().unwrap());
+        fs::write(repo.join(path), contents).unwrap();
+    }
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "-q", "-m", "update"]);
+    git(repo, &["rev-parse", "HEAD"])
+}
@@ -1,12 +1,153 @@
-use std::{fs, path::{self, Path, PathBuf}, io, borrow::Cow, process::Command};
+use std::{collections::BTreeMap, fs, io::{self, Write}, path::{Path, PathBuf}, process::Command};
 
 use super::*;
-use async_recursion::async_recursion;
 use url::Url;
 
 pub fn mkdirp(dir: &Path) {
     // let dir = path::absolute(dir).unwrap();
-    fs::create_dir_all(&dir).expect("should create all directories");
+    fs::create_dir_all(dir).expect("should create all directories");
+}
+
+/// Prints `question` to stderr, keeping stdout for output, and reads a
+/// single trimmed line from stdin.
+pub fn prompt(question: &str) -> String {
+    eprint!("{} ", question);
+    io::stderr().flush().ok();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok();
+    answer.trim().to_owned()
+}
+
+/// First free `<path>.bak`, `<path>.bak.1`, ... next to `path`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = PathBuf::from(format!("{}.bak", path.display()));
+    let mut n = 0;
+    while backup.exists() {
+        n += 1;
+        backup = PathBuf::from(format!("{}.bak.{}", path.display(), n));
+    }
+    backup
+}
+
+/// Moves a file or directory, falling back to copy and delete when `rename`
+/// can't cross filesystems.
+pub fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() { return Ok(()) }
+    copy_path(from, to)?;
+    let parent = from.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    remove_tree(from, parent).map_err(io::Error::other)
+}
+
+/// Fails when `path` is a symlink, which writing to would follow wherever
+/// it points. `shown` names it in the error.
+pub fn refuse_symlink(path: &Path, shown: &str) -> Result<(), String> {
+    match path.symlink_metadata() {
+        Ok(meta) if meta.file_type().is_symlink() => Err(format!("'{}' is a symlink, refusing to write through it", shown)),
+        _ => Ok(()),
+    }
+}
+
+/// Removes `path` and everything below it without following symlinks.
+/// Refuses empty paths, `/` and anything outside of `root`, which `path` may
+/// be itself. Entries that can't be removed are skipped, and reported
+/// together once everything else is gone.
+pub fn remove_tree(path: &Path, root: &Path) -> Result<(), String> {
+    let refuse = || Err(format!("refusing to remove '{}'", path.display()));
+    if path.as_os_str().is_empty() || path.parent().is_none() { return refuse() }
+    let Ok(meta) = path.symlink_metadata() else { return Ok(()) };
+    let root = fs::canonicalize(root).map_err(|e| format!("can't read '{}': {}", root.display(), e))?;
+    // A link is removed itself, wherever it points.
+    let target = match (meta.file_type().is_symlink(), path.parent(), path.file_name()) {
+        (true, Some(parent), Some(name)) => fs::canonicalize(if parent.as_os_str().is_empty() { Path::new(".") } else { parent })
+            .map(|parent| parent.join(name)),
+        _ => fs::canonicalize(path),
+    }.map_err(|e| format!("can't read '{}': {}", path.display(), e))?;
+    if root.parent().is_none() || target.parent().is_none() || !target.starts_with(&root) { return refuse() }
+
+    let mut failures = vec![];
+    remove_entry(&target, &mut failures);
+    match failures.len() {
+        0 => Ok(()),
+        n => Err(format!("couldn't remove {} entries of '{}':\n  {}", n, path.display(), failures.join("\n  "))),
+    }
+}
+
+/// Removes what it can below `path`, then `path` unless something below it
+/// stayed. Returns whether `path` is gone.
+fn remove_entry(path: &Path, failures: &mut Vec<String>) -> bool {
+    let is_dir = path.symlink_metadata().is_ok_and(|meta| meta.is_dir());
+    if is_dir {
+        let mut emptied = true;
+        match fs::read_dir(path) {
+            Ok(entries) => for entry in entries {
+                match entry {
+                    Ok(entry) => emptied &= remove_entry(&entry.path(), failures),
+                    Err(e) => { failures.push(format!("{}: {}", path.display(), e)); emptied = false },
+                }
+            },
+            Err(e) => { failures.push(format!("{}: {}", path.display(), e)); emptied = false },
+        }
+        if !emptied { return false }
+    }
+    match if is_dir { fs::remove_dir(path) } else { fs::remove_file(path) } {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+        Err(e) => { failures.push(format!("{}: {}", path.display(), e)); false },
+    }
+}
+
+/// Recursively copies a file or directory, keeping symlinks as links.
+pub fn copy_path(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = from.symlink_metadata()?;
+    if meta.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_path(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if meta.file_type().is_symlink() {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+pub fn format_utc(timestamp: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil_utc(timestamp);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, hour, minute, second)
+}
+
+/// Splits seconds since the Unix epoch into UTC year, month, day, hour,
+/// minute and second.
+pub fn civil_utc(timestamp: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let (days, secs) = ((timestamp / 86_400) as i64, (timestamp % 86_400) as u32);
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn sha256_file(path: &Path) -> Result<String, String> {
+    use sha2::{Digest, Sha256};
+    let mut file = fs::File::open(path).map_err(|e| format!("can't open '{}': {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(|e| e.to_string())?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
 }
 
 struct FetchOptions {
@@ -31,26 +172,199 @@ impl Default for FetchOptions {
     }
 }
 
-#[async_recursion]
-pub async fn fetch(url: &str, dest: &str, proxy: &str) -> Result<(), String> {
+/// Downloads `url` into `dest`, following redirects. `token` is sent as a
+/// bearer token, but only to the host it was given for.
+pub async fn fetch(url: &str, dest: &str, proxy: &str, token: &str) -> Result<(), String> {
     info!(format!("Fetching remote repository '{}'", url));
-    let res = minreq::get(url).send().unwrap();
-    let code = res.status_code;
-    if code >= 400 {
-        return Err(format!("response failed: '{}'", code));
-    } else if code >= 300 {
-        fetch(res.headers.get("location").unwrap(), dest, proxy).await.unwrap();
-    } else {
-        info!(format!("Saving file to '{}'", dest));
-        mkdirp(Path::new(dest).parent().unwrap());
-        fs::write(dest, res.as_bytes()).expect("failed writing to file");
+    let res = get(url, proxy, token)?;
+    if res.status_code >= 400 {
+        return Err(format!("response failed: '{}'", res.status_code));
     }
-
+    info!(format!("Saving file to '{}'", dest));
+    mkdirp(Path::new(dest).parent().unwrap());
+    fs::write(dest, res.as_bytes()).expect("failed writing to file");
     Ok(())
 }
 
+/// Requests `url` like `fetch` does and returns the final response, whatever
+/// its status.
+pub fn get(url: &str, proxy: &str, token: &str) -> Result<minreq::Response, String> {
+    let mut req = minreq::get(url);
+    if !proxy.is_empty() {
+        req = req.with_proxy(minreq::Proxy::new(proxy).map_err(|e| e.to_string())?);
+    }
+    if !token.is_empty() {
+        req = req.with_header("Authorization", format!("Bearer {}", token));
+    }
+    let res = req.send().map_err(|e| e.to_string())?;
+    if !(300..400).contains(&res.status_code) {
+        return Ok(res);
+    }
+    let location = res.headers.get("location").ok_or("redirect without location")?;
+    let host = |u: &str| Url::parse(u).ok().and_then(|u| u.host_str().map(str::to_owned));
+    let token = if host(location) == host(url) { token } else { "" };
+    get(location, proxy, token)
+}
+
+/// Token configured in `auth` for the host of `url`, or an empty string.
+pub fn token_for<'a>(auth: &'a BTreeMap<String, String>, url: &str) -> &'a str {
+    let host = Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_owned)).unwrap_or_default();
+    auth.get(&host).map_or("", String::as_str)
+}
+
+/// A `git` command for talking to `url` through `proxy`, authenticating over
+/// HTTPS with the token configured for its host. Settings go through
+/// `GIT_CONFIG_*` variables and the token through a credential helper
+/// reading the environment, so neither shows up in the process list.
+pub fn git_remote(url: &str, proxy: &str, auth: &BTreeMap<String, String>) -> Command {
+    let token = token_for(auth, url);
+    let mut config = vec![];
+    if !proxy.is_empty() {
+        config.push(("http.proxy", proxy.to_owned()));
+    }
+    let mut command = Command::new("git");
+    if !token.is_empty() {
+        // Bitbucket wants this user for access tokens, the others take any.
+        let user = match Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_owned)).as_deref() {
+            Some("bitbucket.org") => "x-token-auth",
+            _ => "oauth2",
+        };
+        // The empty helper clears the user's own, so the token is what's used.
+        config.push(("credential.helper", String::new()));
+        config.push(("credential.helper", format!(
+            "!f() {{ test \"$1\" = get && echo username={} && echo \"password=$REGIT_GIT_TOKEN\"; }}; f", user
+        )));
+        command.env("REGIT_GIT_TOKEN", token);
+    }
+    if !config.is_empty() {
+        command.env("GIT_CONFIG_COUNT", config.len().to_string());
+        for (i, (key, value)) in config.iter().enumerate() {
+            command.env(format!("GIT_CONFIG_KEY_{}", i), key).env(format!("GIT_CONFIG_VALUE_{}", i), value);
+        }
+    }
+    command
+}
+
+/// Serves canned `(path, status, body)` responses on a local port for the
+/// rest of the test run and returns the base URL.
+#[cfg(test)]
+pub(crate) fn serve(routes: Vec<(String, u16, Vec<u8>)>) -> String {
+    use std::io::{BufRead, BufReader};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut request_line = String::new();
+            let mut reader = BufReader::new(&stream);
+            reader.read_line(&mut request_line).ok();
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) { line.clear() }
+
+            let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+            let (status, body) = routes.iter()
+                .find(|(p, _, _)| p == path)
+                .map_or((404, &b"not found"[..]), |(_, status, body)| (*status, &body[..]));
+            let head = format!("HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+            stream.write_all(head.as_bytes()).ok();
+            stream.write_all(body).ok();
+        }
+    });
+    base
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-}
\ No newline at end of file
+    #[test]
+    fn hashes_sha256() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.txt");
+        fs::write(&file, "abc").unwrap();
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(sha256_hex(b"abc"), expected);
+        assert_eq!(sha256_file(&file).unwrap(), expected);
+    }
+
+    #[test]
+    fn finds_free_backup_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.txt");
+        assert_eq!(backup_path(&file), tmp.path().join("a.txt.bak"));
+
+        fs::write(tmp.path().join("a.txt.bak"), "").unwrap();
+        assert_eq!(backup_path(&file), tmp.path().join("a.txt.bak.1"));
+    }
+
+    #[test]
+    fn removes_trees_within_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (root, outside) = (tmp.path().join("root"), tmp.path().join("outside"));
+        mkdirp(&root.join("a/b"));
+        mkdirp(&outside);
+        fs::write(root.join("a/b/c.txt"), "").unwrap();
+        fs::write(outside.join("keep.txt"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, root.join("a/link")).unwrap();
+
+        assert!(remove_tree(&outside, &root).is_err(), "outside of the root");
+        assert!(remove_tree(&root.join("../outside"), &root).is_err(), "escapes through '..'");
+        assert!(remove_tree(Path::new(""), &root).is_err());
+        assert!(remove_tree(Path::new("/"), Path::new("/")).is_err());
+
+        remove_tree(&root.join("a"), &root).unwrap();
+        assert!(!root.join("a").exists() && root.is_dir());
+        assert!(outside.join("keep.txt").exists(), "links aren't followed");
+        remove_tree(&root.join("missing"), &root).unwrap();
+        remove_tree(&root, &root).unwrap();
+        assert!(!root.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_what_couldnt_be_removed() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("root");
+        mkdirp(&root.join("locked"));
+        fs::write(root.join("locked/x.txt"), "").unwrap();
+        fs::write(root.join("free.txt"), "").unwrap();
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions don't hold back root.
+        if fs::write(root.join("locked/probe"), "").is_ok() { return }
+
+        let err = remove_tree(&root, &root).unwrap_err();
+        assert!(err.contains("x.txt"), "{}", err);
+        assert!(!root.join("free.txt").exists(), "keeps going past failures");
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn passes_proxy_and_token_to_git() {
+        let auth = BTreeMap::from([(String::from("git.corp"), String::from("s3cret"))]);
+        let output = git_remote("https://git.corp/a/b", "http://proxy:3128", &auth)
+            .args(["config", "--get", "http.proxy"])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "http://proxy:3128");
+
+        let mut git = git_remote("https://git.corp/a/b", "", &auth)
+            .args(["credential", "fill"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        git.stdin.take().unwrap().write_all(b"protocol=https\nhost=git.corp\n\n").unwrap();
+        let filled = String::from_utf8(git.wait_with_output().unwrap().stdout).unwrap();
+        assert!(filled.contains("username=oauth2\npassword=s3cret"), "{}", filled);
+        assert_eq!(token_for(&auth, "https://github.com/a/b"), "");
+    }
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(1_600_000_000), "2020-09-13 12:26:40 UTC");
+        assert_eq!(format_utc(951_825_600), "2000-02-29 12:00:00 UTC");
+    }
+}